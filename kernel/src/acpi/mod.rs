@@ -1,23 +1,20 @@
-use alloc::{prelude::v1::*, sync::Arc};
+use alloc::prelude::v1::*;
 use core::{fmt, mem, ptr::NonNull, result::Result::{Err, Ok}};
-use spin::lock_api::Mutex;
 
-use acpi::{sdt::Signature, AcpiHandler, AcpiTables, PciConfigRegions, PhysicalMapping, Sdt};
+use acpi::{
+    sdt::Signature, AcpiHandler, AcpiTables, HpetInfo, InterruptModel, PciConfigRegions,
+    PhysicalMapping, Sdt,
+};
 use alloc::collections::BTreeMap;
 use bootloader::{boot_info::Optional, BootInfo};
 use volatile::Volatile;
-use x86_64::{PhysAddr, VirtAddr, structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, mapper::MapToError}};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{PageSize, PhysFrame, Size4KiB}};
 
-use crate::{memory_manager::MemoryManager, prelude::*};
+use crate::{memory_manager::{map_mmio, CacheMode, MmioMapping}, prelude::*};
 
-const ACPI_OFFSET: u64 = 0x500000000000;
-
-/// maps region with NO_CACHE set.
+/// Maps ACPI tables and PCI config space through `map_mmio` with caching disabled.
 #[derive(Debug, Clone)]
-struct MapAcpiAddr {
-    physical_offset: *const u8,
-    memory_manager: Arc<Mutex<MemoryManager>>,
-}
+struct MapAcpiAddr;
 
 impl AcpiHandler for MapAcpiAddr {
     unsafe fn map_physical_region<T>(
@@ -25,65 +22,30 @@ impl AcpiHandler for MapAcpiAddr {
         physical_address: usize,
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
-        let mut lock = self.memory_manager.lock();
-        let MemoryManager {
-            ref mut mapper,
-            ref mut frame_allocator,
-        } = *lock;
-        let start_addr = PhysAddr::new(physical_address as u64);
-        let last_addr = PhysAddr::new((physical_address + size - 1) as u64);
-
-        let first_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(start_addr);
-        let last_frame = PhysFrame::containing_address(last_addr);
-
-        let range = PhysFrame::range_inclusive(first_frame, last_frame);
-
-        // println!("Mappin {:?}", range);
-        for frame in range {
-            let addr = VirtAddr::from_ptr(self.physical_offset) + frame.start_address().as_u64();
-            let page = Page::containing_address(addr);
-
-            // println!("ACPI: mapping {:x} to {:x}", frame.start_address(), page.start_address());
-            let map_result = mapper
-                .map_to_with_table_flags(
-                    page,
-                    frame,
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::NO_CACHE
-                        | PageTableFlags::NO_EXECUTE,
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::NO_CACHE
-                        | PageTableFlags::NO_EXECUTE,
-                    frame_allocator,
-                );
-                match map_result {
-                    Ok(flush) => { flush.flush(); }
-                    Err(MapToError::PageAlreadyMapped(_)) => {
-                        // println!("{:x} already mapped", frame.start_address())
-                    }
-                    Err(err) => {
-                        panic!("Error mapping: {:?}", err);
-                    }
-                }
-                
-        }
+        let phys = PhysAddr::new(physical_address as u64);
+        let mapping = map_mmio(phys, size, CacheMode::Uncached)
+            .unwrap_or_else(|err| panic!("Error mapping ACPI region {:?}: {}", phys, err));
+        let mapped_length = (phys + size as u64).align_up(Size4KiB::SIZE)
+            - phys.align_down(Size4KiB::SIZE);
 
         PhysicalMapping {
             physical_start: physical_address,
-            virtual_start: NonNull::new(
-                self.physical_offset.offset(physical_address as isize) as *mut T
-            )
-            .expect("physical_address mapped to 0x0"),
+            virtual_start: NonNull::new(mapping.into_raw().as_mut_ptr())
+                .expect("physical_address mapped to 0x0"),
             region_length: size,
-            mapped_length: range.count() * Size4KiB::SIZE as usize,
+            mapped_length: mapped_length as usize,
             handler: self.clone(),
         }
     }
 
-    fn unmap_physical_region<T>(&self, _: &acpi::PhysicalMapping<Self, T>) {
-        // All memory is mapped so we don't need to unmap it.
+    fn unmap_physical_region<T>(&self, region: &acpi::PhysicalMapping<Self, T>) {
+        drop(unsafe {
+            MmioMapping::from_raw(
+                VirtAddr::from_ptr(region.virtual_start.as_ptr()),
+                PhysAddr::new(region.physical_start as u64),
+                region.region_length,
+            )
+        });
     }
 }
 
@@ -125,15 +87,9 @@ impl fmt::Debug for DebugSdt<'_> {
     }
 }
 
-pub fn init(
-    bootinfo: &mut BootInfo,
-    memory_manager: Arc<Mutex<MemoryManager>>,
-) -> Result<Acpi, AcpiInitError> {
+pub fn init(bootinfo: &mut BootInfo) -> Result<Acpi, AcpiInitError> {
     if let Some(rdsp_addr) = mem::replace(&mut bootinfo.rsdp_addr, Optional::None).into_option() {
-        let acpi_mapper = MapAcpiAddr {
-            physical_offset: ACPI_OFFSET as *const u8,
-            memory_manager: memory_manager.clone(),
-        };
+        let acpi_mapper = MapAcpiAddr;
 
         let tables = unsafe {
             AcpiTables::from_rsdp(acpi_mapper.clone(), rdsp_addr as usize)
@@ -143,6 +99,22 @@ pub fn init(
 
         println!("{:#?}", DebugAcpiTables(&tables));
 
+        let interrupt_model = match tables.platform_info() {
+            Ok(platform_info) => Some(platform_info.interrupt_model),
+            Err(err) => {
+                println!("No platform info in ACPI tables: {:?}", err);
+                None
+            }
+        };
+
+        let hpet_address = match HpetInfo::new(&tables) {
+            Ok(hpet) => Some(PhysAddr::new(hpet.base_address as u64)),
+            Err(err) => {
+                println!("No HPET in ACPI tables: {:?}", err);
+                None
+            }
+        };
+
         let pci_regions = PciConfigRegions::new(&tables).expect("Failed to get PCI regions");
         println!("Enumerating PCI config regions");
         let mut pci_devices: Vec<PciDevice> = Vec::new();
@@ -171,7 +143,11 @@ pub fn init(
         }
         pci_devices.shrink_to_fit();
 
-        Ok(Acpi { pci_devices })
+        Ok(Acpi {
+            pci_devices,
+            interrupt_model,
+            hpet_address,
+        })
     } else {
         Err(AcpiInitError::NoRsdbAddr)
     }
@@ -184,6 +160,20 @@ pub enum AcpiInitError {
 #[derive(Debug)]
 pub struct Acpi {
     pci_devices: Vec<PciDevice>,
+    interrupt_model: Option<InterruptModel>,
+    hpet_address: Option<PhysAddr>,
+}
+
+impl Acpi {
+    /// Interrupt controllers described by the MADT.
+    pub fn interrupt_model(&self) -> Option<&InterruptModel> {
+        self.interrupt_model.as_ref()
+    }
+
+    /// Physical address of the HPET's registers, from the HPET table.
+    pub fn hpet_address(&self) -> Option<PhysAddr> {
+        self.hpet_address
+    }
 }
 
 #[derive(Debug)]
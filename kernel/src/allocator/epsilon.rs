@@ -2,7 +2,6 @@
 
 use core::alloc::{GlobalAlloc, Layout};
 use spin::lock_api::Mutex;
-use x86_64::VirtAddr;
 
 pub struct EpsilonAllocatorLocked {
     alloc: Mutex<Option<EpsilonAllocator>>,
@@ -14,15 +13,17 @@ impl EpsilonAllocatorLocked {
         }
     }
 
-    pub fn init(&self, addr: VirtAddr, size: u64) {
+    /// # Safety
+    /// `start..start + size` must be mapped and unused.
+    pub unsafe fn init(&self, start: usize, size: usize) {
         let mut g = self.alloc.lock();
         if g.is_some() {
             panic!("Allocator already initialized");
         }
         *g = Some(EpsilonAllocator {
-            _start: addr.as_u64(),
-            next: addr.as_u64(),
-            remaining: size,
+            _start: start as u64,
+            next: start as u64,
+            remaining: size as u64,
         });
     }
 }
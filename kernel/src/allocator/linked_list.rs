@@ -0,0 +1,53 @@
+// src/allocator/linked_list.rs
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+/// `linked_list_allocator` heap that maps more pages when it runs out.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> GrowableHeap {
+        GrowableHeap {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+
+    /// # Safety
+    /// `start..start + size` must be mapped, unused and directly followed by the rest of the
+    /// heap region handed out by `super::grow_heap`.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.heap.lock().init(start, size);
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Worst case the new memory is split from the old free block by alignment padding.
+        let wanted = layout.size() + layout.align();
+        while let Some((_, grown)) = super::grow_heap(wanted) {
+            heap.extend(grown as usize);
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
@@ -2,36 +2,131 @@
 
 pub mod dummy;
 pub mod epsilon;
-// pub mod list_allocator;
+#[cfg(feature = "linked_list_allocator")]
+pub mod linked_list;
+#[cfg(feature = "slab_allocator")]
+pub mod slab;
+pub mod stats;
+
+use core::sync::atomic::{AtomicU64, Ordering};
 
-#[cfg(epsilon)]
+#[cfg(feature = "epsilon_allocator")]
 use epsilon::EpsilonAllocatorLocked;
-use linked_list_allocator::LockedHeap;
+#[cfg(feature = "linked_list_allocator")]
+use linked_list::GrowableHeap;
+#[cfg(feature = "slab_allocator")]
+use slab::SlabAllocator;
+use stats::Instrumented;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-use crate::prelude::*;
+use crate::{memory_manager, prelude::*};
 
 pub const HEAP_START: u64 = 0x4444_4444_0000;
-pub const HEAP_SIZE: u64 = 64 * 1024; // 64 KiB
+/// Size mapped by `init_heap`.
+pub const HEAP_INITIAL_SIZE: u64 = 64 * 1024; // 64 KiB
+/// Default ceiling the heap may grow to. Can be changed with `set_heap_limit`.
+pub const HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
+/// Smallest amount the heap grows by, to avoid taking the memory manager lock for every page.
+const HEAP_GROW_STEP: u64 = 64 * 1024;
+
+/// End of the currently mapped heap.
+static HEAP_END: AtomicU64 = AtomicU64::new(HEAP_START);
+/// Address the heap may not grow past.
+static HEAP_LIMIT: AtomicU64 = AtomicU64::new(HEAP_START + HEAP_MAX_SIZE);
 
 #[cfg(feature = "linked_list_allocator")]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Instrumented<GrowableHeap> = Instrumented::new(GrowableHeap::empty());
 
 #[cfg(feature = "epsilon_allocator")]
 #[global_allocator]
-static ALLOCATOR: EpsilonAllocatorLocked = EpsilonAllocatorLocked::new();
+static ALLOCATOR: Instrumented<EpsilonAllocatorLocked> =
+    Instrumented::new(EpsilonAllocatorLocked::new());
+
+#[cfg(feature = "slab_allocator")]
+#[global_allocator]
+static ALLOCATOR: Instrumented<SlabAllocator> = Instrumented::new(SlabAllocator::empty());
+
+#[cfg(any(
+    all(feature = "linked_list_allocator", feature = "epsilon_allocator"),
+    all(feature = "linked_list_allocator", feature = "slab_allocator"),
+    all(feature = "epsilon_allocator", feature = "slab_allocator"),
+))]
+compile_error!(
+    "Only one allocator feature may be enabled. Use --no-default-features to pick a different one."
+);
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout);
+    panic!(
+        "allocation error: {:?} (heap is {} of {} bytes)",
+        layout,
+        heap_size(),
+        heap_limit()
+    );
+}
+
+/// Bytes of virtual memory currently mapped for the heap.
+pub fn heap_size() -> u64 {
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Maximum size the heap may grow to.
+pub fn heap_limit() -> u64 {
+    HEAP_LIMIT.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Largest limit `set_heap_limit` accepts, the heap must stay below the dynamic region.
+pub const HEAP_MAX_LIMIT: u64 = memory_manager::DYNAMIC_REGION_START - HEAP_START;
+
+/// Change the maximum size the heap may grow to, clamped to `HEAP_MAX_LIMIT`. Returns the
+/// limit now in effect.
+///
+/// Memory already mapped is never released. A limit below `heap_size()` just stops the heap
+/// from growing any further.
+pub fn set_heap_limit(max_size: u64) -> u64 {
+    let max_size = max_size.min(HEAP_MAX_LIMIT);
+    HEAP_LIMIT.store(HEAP_START + max_size, Ordering::Relaxed);
+    max_size
 }
 
+/// Map at least `min_bytes` more memory directly after the end of the heap.
+///
+/// Returns the start and size of the new memory, or `None` if the heap is at its limit,
+/// the memory manager is not yet initialized or physical memory has run out.
+///
+/// Blocks if the memory manager is locked. It is only ever held with interrupts disabled and
+/// never while allocating, so whoever holds it isn't the code allocating and will let go.
+pub(crate) fn grow_heap(min_bytes: usize) -> Option<(VirtAddr, u64)> {
+    let mut memory_manager = memory_manager::try_memory_manager()?.lock();
+
+    let start = HEAP_END.load(Ordering::Relaxed);
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    let wanted = align_up(min_bytes as u64, Size4KiB::SIZE).max(HEAP_GROW_STEP);
+    let size = wanted.min(limit.saturating_sub(start));
+    if size < min_bytes as u64 || size == 0 {
+        return None;
+    }
+
+    let pages = Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(start + size - 1)),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory_manager.map_fresh(pages, flags).ok()?;
+
+    HEAP_END.store(start + size, Ordering::Relaxed);
+    Some((VirtAddr::new(start), size))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -39,7 +134,7 @@ pub fn init_heap(
 ) -> Result<(), MapToError<Size4KiB>> {
     let heap_start = VirtAddr::new(HEAP_START);
     let page_range = {
-        let heap_last_addr = VirtAddr::new(HEAP_START + HEAP_SIZE - 1);
+        let heap_last_addr = VirtAddr::new(HEAP_START + HEAP_INITIAL_SIZE - 1);
         let heap_start_page = Page::containing_address(heap_start);
         let heap_last_page = Page::containing_address(heap_last_addr);
         Page::range_inclusive(heap_start_page, heap_last_page)
@@ -50,7 +145,7 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
@@ -58,10 +153,11 @@ pub fn init_heap(
     }
 
     let start = page_range.start.start_address();
+    HEAP_END.store(HEAP_START + HEAP_INITIAL_SIZE, Ordering::Relaxed);
     unsafe {
         ALLOCATOR
-            .lock()
-            .init(start.as_u64() as usize, HEAP_SIZE as usize);
+            .inner()
+            .init(start.as_u64() as usize, HEAP_INITIAL_SIZE as usize);
     }
 
     Ok(())
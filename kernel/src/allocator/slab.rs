@@ -0,0 +1,329 @@
+// src/allocator/slab.rs
+
+//! Size-class allocator.
+//!
+//! Small allocations are served from per-size-class caches. Each cache carves whole pages
+//! (slabs) into equally sized objects and keeps freed objects on an intrusive free list, so
+//! the `Task`, `Waker` and `BTreeMap` node churn of the executor never touches the page
+//! allocator. Anything bigger than the largest class is rounded up to whole pages and served
+//! by a first-fit page allocator over the heap region, which grows through `super::grow_heap`.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+pub struct SlabAllocator {
+    state: Mutex<SlabState>,
+}
+
+struct SlabState {
+    caches: [SizeClassCache; SIZE_CLASSES.len()],
+    pages: PageAllocator,
+}
+
+// The raw pointers only ever point into the heap region, which is owned by the allocator.
+unsafe impl Send for SlabState {}
+
+impl SlabAllocator {
+    pub const fn empty() -> SlabAllocator {
+        SlabAllocator {
+            state: Mutex::new(SlabState {
+                caches: [
+                    SizeClassCache::new(SIZE_CLASSES[0]),
+                    SizeClassCache::new(SIZE_CLASSES[1]),
+                    SizeClassCache::new(SIZE_CLASSES[2]),
+                    SizeClassCache::new(SIZE_CLASSES[3]),
+                    SizeClassCache::new(SIZE_CLASSES[4]),
+                    SizeClassCache::new(SIZE_CLASSES[5]),
+                    SizeClassCache::new(SIZE_CLASSES[6]),
+                    SizeClassCache::new(SIZE_CLASSES[7]),
+                    SizeClassCache::new(SIZE_CLASSES[8]),
+                ],
+                pages: PageAllocator { free: None },
+            }),
+        }
+    }
+
+    /// # Safety
+    /// `start..start + size` must be mapped, unused, page aligned and directly followed by
+    /// the rest of the heap region handed out by `super::grow_heap`.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.state.lock().pages.free_pages(start, size / PAGE_SIZE);
+    }
+}
+
+/// Index of the smallest size class that satisfies `layout`, if any.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+fn pages_for(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut state = self.state.lock();
+        let SlabState { caches, pages } = &mut *state;
+        match size_class(&layout) {
+            Some(class) => caches[class].alloc(pages),
+            None => {
+                let align = pages_for(layout.align()).max(1);
+                pages.alloc_pages(pages_for(layout.size()), align)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+        match size_class(&layout) {
+            Some(class) => state.caches[class].dealloc(ptr),
+            None => state
+                .pages
+                .free_pages(ptr as usize, pages_for(layout.size())),
+        }
+    }
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct SizeClassCache {
+    object_size: usize,
+    free: Option<NonNull<FreeObject>>,
+}
+
+impl SizeClassCache {
+    const fn new(object_size: usize) -> SizeClassCache {
+        SizeClassCache {
+            object_size,
+            free: None,
+        }
+    }
+
+    unsafe fn alloc(&mut self, pages: &mut PageAllocator) -> *mut u8 {
+        if self.free.is_none() && !self.refill(pages) {
+            return ptr::null_mut();
+        }
+        let object = self.free.take().unwrap();
+        self.free = object.as_ref().next;
+        object.as_ptr() as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: self.free });
+        self.free = NonNull::new(object);
+    }
+
+    /// Carve a new page into objects. Objects stay aligned to their size as slabs are pages.
+    unsafe fn refill(&mut self, pages: &mut PageAllocator) -> bool {
+        let slab = pages.alloc_pages(1, 1);
+        if slab.is_null() {
+            return false;
+        }
+        for offset in (0..PAGE_SIZE).step_by(self.object_size).rev() {
+            self.dealloc(slab.add(offset));
+        }
+        true
+    }
+}
+
+struct FreeRun {
+    pages: usize,
+    next: Option<NonNull<FreeRun>>,
+}
+
+/// First-fit allocator of whole pages, kept as an address ordered list of free runs.
+struct PageAllocator {
+    free: Option<NonNull<FreeRun>>,
+}
+
+impl PageAllocator {
+    unsafe fn alloc_pages(&mut self, count: usize, align: usize) -> *mut u8 {
+        loop {
+            if let Some(ptr) = self.take_from_free_list(count, align) {
+                return ptr;
+            }
+            // Enough to guarantee an aligned fit even if the new memory isn't merged.
+            let wanted = (count + align - 1) * PAGE_SIZE;
+            match super::grow_heap(wanted) {
+                Some((start, size)) => {
+                    self.free_pages(start.as_u64() as usize, size as usize / PAGE_SIZE)
+                }
+                None => return ptr::null_mut(),
+            }
+        }
+    }
+
+    unsafe fn take_from_free_list(&mut self, count: usize, align: usize) -> Option<*mut u8> {
+        let align_bytes = align * PAGE_SIZE;
+        let mut link: *mut Option<NonNull<FreeRun>> = &mut self.free;
+
+        while let Some(run) = *link {
+            let run_start = run.as_ptr() as usize;
+            let run_pages = run.as_ref().pages;
+            let run_end = run_start + run_pages * PAGE_SIZE;
+            let start = (run_start + align_bytes - 1) & !(align_bytes - 1);
+            let end = start + count * PAGE_SIZE;
+
+            if end <= run_end {
+                // Unlink the run, then give back whatever is either side of the allocation.
+                *link = run.as_ref().next;
+                if start > run_start {
+                    self.free_pages(run_start, (start - run_start) / PAGE_SIZE);
+                }
+                if run_end > end {
+                    self.free_pages(end, (run_end - end) / PAGE_SIZE);
+                }
+                return Some(start as *mut u8);
+            }
+            link = &mut (*run.as_ptr()).next;
+        }
+        None
+    }
+
+    /// Return `count` pages at `start` to the free list, merging with neighbouring runs.
+    unsafe fn free_pages(&mut self, start: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let end = start + count * PAGE_SIZE;
+
+        // Find the last run before `start`.
+        let mut prev: Option<NonNull<FreeRun>> = None;
+        let mut next = self.free;
+        while let Some(run) = next {
+            if run.as_ptr() as usize > start {
+                break;
+            }
+            prev = Some(run);
+            next = run.as_ref().next;
+        }
+
+        let mut new_run = NonNull::new_unchecked(start as *mut FreeRun);
+        new_run.as_ptr().write(FreeRun { pages: count, next });
+
+        // Merge with the following run.
+        if let Some(following) = next {
+            if following.as_ptr() as usize == end {
+                let following = following.as_ref();
+                new_run.as_mut().pages += following.pages;
+                new_run.as_mut().next = following.next;
+            }
+        }
+
+        match prev {
+            Some(mut prev) => {
+                let prev_end = prev.as_ptr() as usize + prev.as_ref().pages * PAGE_SIZE;
+                if prev_end == start {
+                    prev.as_mut().pages += new_run.as_ref().pages;
+                    prev.as_mut().next = new_run.as_ref().next;
+                } else {
+                    prev.as_mut().next = Some(new_run);
+                }
+            }
+            None => self.free = Some(new_run),
+        }
+    }
+}
+
+#[test_case]
+fn size_classes_cover_alignment() {
+    assert_eq!(size_class(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+    assert_eq!(size_class(&Layout::from_size_align(24, 8).unwrap()), Some(2));
+    assert_eq!(size_class(&Layout::from_size_align(8, 256).unwrap()), Some(5));
+    assert_eq!(size_class(&Layout::from_size_align(2049, 8).unwrap()), None);
+    assert_eq!(size_class(&Layout::from_size_align(16, 4096).unwrap()), None);
+}
+
+/// Pages for allocators made by tests, so they don't grow the real heap.
+#[repr(align(4096))]
+struct TestArena([u8; TEST_ARENA_PAGES * PAGE_SIZE]);
+
+const TEST_ARENA_PAGES: usize = 16;
+static mut TEST_ARENA: TestArena = TestArena([0; TEST_ARENA_PAGES * PAGE_SIZE]);
+
+fn test_allocator() -> (SlabAllocator, usize) {
+    let allocator = SlabAllocator::empty();
+    let start = unsafe { TEST_ARENA.0.as_mut_ptr() as usize };
+    unsafe { allocator.init(start, TEST_ARENA_PAGES * PAGE_SIZE) };
+    (allocator, start)
+}
+
+#[test_case]
+fn freed_objects_are_reused() {
+    let (allocator, _) = test_allocator();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        let second = allocator.alloc(layout);
+        assert!(!first.is_null() && !second.is_null());
+        assert_ne!(first, second);
+        // Both come from the same 32 byte slab.
+        assert_eq!(first as usize / PAGE_SIZE, second as usize / PAGE_SIZE);
+        assert_eq!(first as usize % 32, 0);
+
+        allocator.dealloc(first, layout);
+        assert_eq!(allocator.alloc(layout), first);
+        allocator.dealloc(first, layout);
+        allocator.dealloc(second, layout);
+    }
+}
+
+#[test_case]
+fn emptied_slabs_are_reused() {
+    let (allocator, _) = test_allocator();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut objects = [ptr::null_mut(); PAGE_SIZE / 64];
+    unsafe {
+        for object in objects.iter_mut() {
+            *object = allocator.alloc(layout);
+        }
+        let slab = objects[0] as usize / PAGE_SIZE;
+        assert!(objects.iter().all(|&o| o as usize / PAGE_SIZE == slab));
+
+        for &object in objects.iter() {
+            allocator.dealloc(object, layout);
+        }
+        // The free list covers the whole slab again, so no new page is carved up.
+        for object in objects.iter_mut() {
+            *object = allocator.alloc(layout);
+            assert_eq!(*object as usize / PAGE_SIZE, slab);
+        }
+        for &object in objects.iter() {
+            allocator.dealloc(object, layout);
+        }
+    }
+}
+
+#[test_case]
+fn large_allocations_take_whole_pages() {
+    let (allocator, start) = test_allocator();
+    let end = start + TEST_ARENA_PAGES * PAGE_SIZE;
+    let layout = Layout::from_size_align(3 * PAGE_SIZE - 100, 8).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        assert_eq!(first as usize % PAGE_SIZE, 0);
+        assert!(start <= first as usize && first as usize + 3 * PAGE_SIZE <= end);
+
+        let aligned = Layout::from_size_align(PAGE_SIZE, 4 * PAGE_SIZE).unwrap();
+        let second = allocator.alloc(aligned);
+        assert_eq!(second as usize % (4 * PAGE_SIZE), 0);
+        assert!(second as usize >= first as usize + 3 * PAGE_SIZE);
+
+        // Freed pages merge back and are handed out again.
+        allocator.dealloc(first, layout);
+        allocator.dealloc(second, aligned);
+        assert_eq!(allocator.alloc(layout), start as *mut u8);
+        allocator.dealloc(start as *mut u8, layout);
+    }
+}
@@ -0,0 +1,328 @@
+// src/allocator/stats.rs
+
+//! Heap instrumentation.
+//!
+//! `Instrumented` wraps the global allocator and keeps counters of live bytes, peak usage and
+//! allocations per power-of-two size bucket. `snapshot` captures them and `HeapStats::since`
+//! diffs two snapshots, so a test can check a workload frees everything it allocates.
+//!
+//! With `set_tracking(true)` the address and size of every live allocation is also recorded,
+//! along with a return address into the code that made it, see `tracked_allocations`. That
+//! address comes from the frame pointer chain, which `.cargo/config.toml` forces on.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Buckets hold allocations of up to 8, 16, 32 ... 32KiB bytes. The last one holds the rest.
+pub const BUCKET_COUNT: usize = 14;
+const MIN_BUCKET_SHIFT: u32 = 3;
+const TRACKING_SLOTS: usize = 512;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static BUCKET_ALLOCATIONS: [AtomicU64; BUCKET_COUNT] = [ZERO; BUCKET_COUNT];
+static BUCKET_DEALLOCATIONS: [AtomicU64; BUCKET_COUNT] = [ZERO; BUCKET_COUNT];
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKED: Mutex<TrackingTable> = Mutex::new(TrackingTable {
+    slots: [TrackedAllocation::EMPTY; TRACKING_SLOTS],
+    untracked: 0,
+});
+
+/// Global allocator wrapper that keeps heap statistics.
+pub struct Instrumented<A> {
+    inner: A,
+}
+
+impl<A> Instrumented<A> {
+    pub const fn new(inner: A) -> Instrumented<A> {
+        Instrumented { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Instrumented<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let live = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+            BUCKET_ALLOCATIONS[bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+            if TRACKING.load(Ordering::Relaxed) {
+                let caller = caller_address();
+                with_table(|table| table.insert(ptr as usize, layout.size(), caller));
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        BUCKET_DEALLOCATIONS[bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+        if TRACKING.load(Ordering::Relaxed) {
+            with_table(|table| table.remove(ptr as usize));
+        }
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    let bits = (core::mem::size_of::<usize>() * 8) as u32;
+    let shift = bits - size.saturating_sub(1).leading_zeros();
+    (shift.saturating_sub(MIN_BUCKET_SHIFT) as usize).min(BUCKET_COUNT - 1)
+}
+
+/// Largest size that falls in `bucket`, or `None` for the last, unbounded, bucket.
+pub fn bucket_limit(bucket: usize) -> Option<usize> {
+    if bucket + 1 < BUCKET_COUNT {
+        Some(1 << (bucket as u32 + MIN_BUCKET_SHIFT))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl BucketStats {
+    pub fn live(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+}
+
+/// Point in time copy of the heap counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub failed: u64,
+    pub buckets: [BucketStats; BUCKET_COUNT],
+}
+
+pub fn snapshot() -> HeapStats {
+    let mut buckets = [BucketStats::default(); BUCKET_COUNT];
+    for (i, b) in buckets.iter_mut().enumerate() {
+        b.allocations = BUCKET_ALLOCATIONS[i].load(Ordering::Relaxed);
+        b.deallocations = BUCKET_DEALLOCATIONS[i].load(Ordering::Relaxed);
+    }
+    HeapStats {
+        live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        buckets,
+    }
+}
+
+impl HeapStats {
+    pub fn allocations(&self) -> u64 {
+        self.buckets.iter().map(|b| b.allocations).sum()
+    }
+
+    pub fn deallocations(&self) -> u64 {
+        self.buckets.iter().map(|b| b.deallocations).sum()
+    }
+
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations() - self.deallocations()
+    }
+
+    /// What happened between `earlier` and this snapshot.
+    pub fn since(&self, earlier: &HeapStats) -> HeapStatsDiff {
+        let mut live = [0; BUCKET_COUNT];
+        for (i, l) in live.iter_mut().enumerate() {
+            *l = self.buckets[i].live() as i64 - earlier.buckets[i].live() as i64;
+        }
+        HeapStatsDiff {
+            live_bytes: self.live_bytes as i64 - earlier.live_bytes as i64,
+            allocations: self.allocations() - earlier.allocations(),
+            deallocations: self.deallocations() - earlier.deallocations(),
+            failed: self.failed - earlier.failed,
+            live,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} bytes live in {} allocations, peak {} bytes, {} failed",
+            self.live_bytes,
+            self.live_allocations(),
+            self.peak_bytes,
+            self.failed
+        )?;
+        for (i, b) in self.buckets.iter().enumerate() {
+            if b.allocations == 0 {
+                continue;
+            }
+            match bucket_limit(i) {
+                Some(limit) => write!(f, "  <= {:>6}:", limit)?,
+                None => write!(f, "   > {:>6}:", bucket_limit(BUCKET_COUNT - 2).unwrap())?,
+            }
+            writeln!(f, " {:>8} allocs {:>8} live", b.allocations, b.live())?;
+        }
+        Ok(())
+    }
+}
+
+/// Difference between two `HeapStats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStatsDiff {
+    pub live_bytes: i64,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub failed: u64,
+    /// Change in live allocations per bucket.
+    pub live: [i64; BUCKET_COUNT],
+}
+
+impl HeapStatsDiff {
+    /// True if anything allocated in the interval is still alive.
+    pub fn leaked(&self) -> bool {
+        self.live_bytes != 0 || self.live.iter().any(|&l| l != 0)
+    }
+}
+
+impl fmt::Display for HeapStatsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:+} bytes live, {} allocations, {} deallocations, {} failed",
+            self.live_bytes, self.allocations, self.deallocations, self.failed
+        )
+    }
+}
+
+/// Record every allocation made from now on, along with where it was made.
+///
+/// Allocations made while tracking was off are not in the table.
+pub fn set_tracking(enabled: bool) {
+    if enabled {
+        with_table(|table| {
+            table.slots = [TrackedAllocation::EMPTY; TRACKING_SLOTS];
+            table.untracked = 0;
+        });
+    }
+    TRACKING.store(enabled, Ordering::SeqCst);
+}
+
+/// A live allocation recorded while tracking was enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackedAllocation {
+    pub address: usize,
+    pub size: usize,
+    /// Return address one frame above the allocator, 0 if the frame chain looked broken.
+    /// Points at the allocating code, or at the `alloc` crate function it called if that
+    /// wasn't inlined.
+    pub caller: usize,
+}
+
+impl TrackedAllocation {
+    const EMPTY: TrackedAllocation = TrackedAllocation {
+        address: 0,
+        size: 0,
+        caller: 0,
+    };
+}
+
+/// Run `f` with the tracking table locked.
+///
+/// Interrupts are off while the lock is held, an interrupt handler that allocates would
+/// otherwise spin on it forever.
+fn with_table<R>(f: impl FnOnce(&mut TrackingTable) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TRACKED.lock()))
+}
+
+struct TrackingTable {
+    slots: [TrackedAllocation; TRACKING_SLOTS],
+    /// Allocations that didn't fit in the table.
+    untracked: usize,
+}
+
+impl TrackingTable {
+    fn insert(&mut self, address: usize, size: usize, caller: usize) {
+        match self.slots.iter_mut().find(|slot| slot.address == 0) {
+            Some(slot) => {
+                *slot = TrackedAllocation {
+                    address,
+                    size,
+                    caller,
+                }
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    fn remove(&mut self, address: usize) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.address == address) {
+            *slot = TrackedAllocation::EMPTY;
+        }
+    }
+}
+
+/// Call `f` with every tracked live allocation. Returns the number of allocations that were
+/// made while tracking but didn't fit in the table.
+///
+/// The tracking table is locked and interrupts are disabled while `f` runs. While tracking is
+/// on, every allocation and deallocation takes that lock too, so `f` must not allocate or free
+/// memory or it deadlocks.
+pub fn tracked_allocations(mut f: impl FnMut(&TrackedAllocation)) -> usize {
+    with_table(|table| {
+        for slot in table.slots.iter().filter(|slot| slot.address != 0) {
+            f(slot);
+        }
+        table.untracked
+    })
+}
+
+/// Return address of the function that called the one `Instrumented::alloc` returns to.
+///
+/// Only taken while tracking is on. Takes a single step up the frame pointer chain, and gives
+/// up with 0 if the saved frame pointer doesn't point further up the stack.
+#[inline(always)]
+fn caller_address() -> usize {
+    const MAX_STACK: usize = 1024 * 1024;
+
+    let (rbp, rsp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp);
+    }
+    let looks_valid = |frame: usize| frame >= rsp && frame - rsp <= MAX_STACK && frame % 8 == 0;
+    if !looks_valid(rbp) {
+        return 0;
+    }
+    // `rbp` is `alloc`'s own frame, the saved one above it belongs to its caller.
+    let caller_frame = unsafe { *(rbp as *const usize) };
+    if caller_frame <= rbp || !looks_valid(caller_frame) {
+        return 0;
+    }
+    unsafe { *((caller_frame + 8) as *const usize) }
+}
+
+#[test_case]
+fn buckets_are_powers_of_two() {
+    assert_eq!(bucket(1), 0);
+    assert_eq!(bucket(8), 0);
+    assert_eq!(bucket(9), 1);
+    assert_eq!(bucket(4096), 9);
+    assert_eq!(bucket(4097), 10);
+    assert_eq!(bucket(usize::MAX / 2), BUCKET_COUNT - 1);
+    assert_eq!(bucket_limit(9), Some(4096));
+}
@@ -9,10 +9,12 @@ use smallvec::SmallVec;
 use ux::{u28, u4};
 use x86_64::{instructions::{interrupts::without_interrupts, port::{Port, PortReadOnly, PortWriteOnly}}, structures::port::{PortRead, PortWrite}};
 
-use crate::{prelude::*, tasks::timer::current_tick};
+use crate::{prelude::*, time::{Duration, Instant}};
 use crate::irq::InterruptIndex;
 
 const SECTOR_SIZE: usize = 512;
+/// How long `wait_for_bsy` waits for a drive to become ready.
+const BSY_TIMEOUT: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 struct Bus {
@@ -315,14 +317,14 @@ impl Bus {
     }
 
     fn wait_for_bsy(&mut self) -> Result<(), ()> {
-        let start_ticks = current_tick();
+        let start = Instant::now();
         let mut port = self.alt_status();
         loop {
             let flag = unsafe { port.read() };
             
             if flag.contains(StatusRegister::RDY) && !flag.contains(StatusRegister::BSY) {
                 return Ok(());
-            } else if current_tick() - start_ticks > 1 {
+            } else if start.elapsed() > BSY_TIMEOUT {
                 return Err(())
             }
         }
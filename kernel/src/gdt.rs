@@ -1,5 +1,6 @@
 // src/gdt.rs
 
+use core::ptr::{addr_of, addr_of_mut};
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
@@ -7,34 +8,42 @@ use x86_64::instructions::segmentation::set_cs;
 use x86_64::instructions::tables::load_tss;
 use lazy_static::lazy_static;
 
+use crate::memory_manager::KernelStack;
 use crate::prelude::*;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe {
-                &STACK[0] as *const u8
-            });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end            
-        };
-
-        tss
-    };
+pub const IST_STACK_SIZE: u64 = 4096 * 5;
+
+/// Stacks for the IST entries, one each, until `init_ist_stacks` moves them onto stacks with
+/// guard pages. Sharing one would let an NMI during a double fault overwrite its frame.
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+const IST_ENTRIES: usize = 3;
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; IST_ENTRIES] = [[0; BOOT_STACK_SIZE]; IST_ENTRIES];
+
+// Only written before the TSS is loaded and by `init_ist_stacks`. The CPU reads the IST
+// entries when it takes an exception, so updating them later is fine. Only ever accessed
+// through raw pointers, as the entries change while the GDT refers to it.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Point IST entry `index` at `stack_end`.
+///
+/// # Safety
+/// No exception may be using the entry's old stack.
+unsafe fn set_ist_entry(index: u16, stack_end: VirtAddr) {
+    (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = stack_end;
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        
+        // The descriptor only takes the TSS's address and size, the reference doesn't outlive
+        // this call.
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -46,9 +55,34 @@ struct Selectors {
 
 pub fn init() {
     println!("initializing gdt");
+    for &index in &[DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+        unsafe {
+            let stack = addr_of!(BOOT_STACKS[index as usize]);
+            set_ist_entry(index, VirtAddr::from_ptr(stack) + BOOT_STACK_SIZE);
+        }
+    }
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Move the IST entries onto their own stacks with guard pages.
+///
+/// Called by `memory_manager::init` once stacks can be allocated.
+pub fn init_ist_stacks() {
+    let stacks = [
+        (DOUBLE_FAULT_IST_INDEX, "double fault handler"),
+        (NMI_IST_INDEX, "NMI handler"),
+        (MACHINE_CHECK_IST_INDEX, "machine check handler"),
+    ];
+    for &(index, owner) in &stacks {
+        let top = KernelStack::new(IST_STACK_SIZE, owner)
+            .unwrap_or_else(|err| panic!("Failed to allocate {} stack: {}", owner, err))
+            .leak();
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            set_ist_entry(index, top);
+        });
+    }
+}
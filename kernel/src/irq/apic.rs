@@ -1,9 +1,39 @@
+// src/irq/apic.rs
 
-use x86_64::VirtAddr;
+//! Local APIC and I/O APIC support.
+//!
+//! `init` finds the controllers in the ACPI MADT, masks the 8259s and routes the ISA IRQs
+//! the kernel uses through the I/O APIC, on the same vectors the PICs used.
+
+use acpi::{
+    platform::{InterruptSourceOverride, Polarity, TriggerMode},
+    InterruptModel,
+};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    ptr::{addr_of, addr_of_mut},
+};
+use spin::Mutex;
 use volatile::Volatile;
+use x86_64::{instructions::interrupts, registers::model_specific::Msr, PhysAddr};
+
+use super::{dispatch, pic_8256};
+use crate::memory_manager::{map_mmio, CacheMode, MmioError, MmioMapping};
+
+/// Vector the local APIC raises for spurious interrupts. They must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// ISA IRQ 2 is the PIC cascade and never raised by a device.
+const ISA_CASCADE_IRQ: u8 = 2;
 
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
 
-// src/apic.rs
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+/// Where each ISA IRQ is wired, and the APIC id to deliver to.
+static ISA_ROUTES: OnceCell<([IsaRoute; 16], u8)> = OnceCell::uninit();
 
 #[repr(align(16))]
 struct Reserved {
@@ -11,36 +41,48 @@ struct Reserved {
     _padding: [u32; 3],
 }
 
+// Registers are only ever reached through raw pointers, each access a single volatile read
+// or write. Interrupt handlers write the EOI register while other code may be using the
+// block, so no reference to it may exist.
+
 #[repr(align(16))]
 pub struct ReadOnlyRegister {
-    reg: Volatile<u32>,
+    reg: u32,
     _padding: [u32; 3],
 }
 
 impl ReadOnlyRegister {
-    /// IO registers can have effects when the read. So this is protected with mut
-    fn read(&mut self) -> u32 { self.reg.read() }
+    /// # Safety
+    /// `this` must point at a mapped register.
+    unsafe fn read(this: *const Self) -> u32 { addr_of!((*this).reg).read_volatile() }
 }
 
 #[repr(align(16))]
 pub struct WriteOnlyRegister {
-    reg: Volatile<u32>,
+    reg: u32,
     _padding: [u32; 3],
 }
 
 impl WriteOnlyRegister {
-    fn write(&mut self, value: u32) { self.reg.write(value) }
+    /// # Safety
+    /// `this` must point at a mapped register.
+    unsafe fn write(this: *mut Self, value: u32) { addr_of_mut!((*this).reg).write_volatile(value) }
 }
 
 #[repr(align(16))]
 pub struct ReadWriteRegister {
-    reg: Volatile<u32>,
+    reg: u32,
     _padding: [u32; 3],
 }
 
 impl ReadWriteRegister {
-    fn read(&mut self) -> u32 { self.reg.read() }
-    fn write(&mut self, value: u32) { self.reg.write(value) }
+    /// # Safety
+    /// `this` must point at a mapped register.
+    unsafe fn read(this: *const Self) -> u32 { addr_of!((*this).reg).read_volatile() }
+
+    /// # Safety
+    /// `this` must point at a mapped register.
+    unsafe fn write(this: *mut Self, value: u32) { addr_of_mut!((*this).reg).write_volatile(value) }
 }
 
 #[repr(C)]
@@ -109,25 +151,281 @@ impl IoApicRegisters {
     }
 }
 
-struct Apic {
-    // This is fine because we need a Mutable reference to Apic to perform mutations.
-    registers: &'static mut MappedRegisters,
+#[derive(Debug)]
+pub enum ApicError {
+    /// The MADT describes no APIC, or wasn't found.
+    NotPresent,
+    NoIoApic,
+    /// No I/O APIC has an input for this global system interrupt.
+    UnknownGsi(u32),
+    Map(MmioError),
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApicError::NotPresent => write!(f, "no APIC described by ACPI"),
+            ApicError::NoIoApic => write!(f, "no I/O APIC described by ACPI"),
+            ApicError::UnknownGsi(gsi) => write!(f, "no I/O APIC input for GSI {}", gsi),
+            ApicError::Map(err) => write!(f, "failed to map APIC registers: {}", err),
+        }
+    }
+}
+
+impl crate::error::Error for ApicError {}
+
+impl From<MmioError> for ApicError {
+    fn from(err: MmioError) -> ApicError {
+        ApicError::Map(err)
+    }
+}
+
+pub struct LocalApic {
+    registers: *mut MappedRegisters,
+    _mapping: MmioMapping,
+}
+
+// The registers are per CPU and every access is a single volatile read or write.
+unsafe impl Send for LocalApic {}
+unsafe impl Sync for LocalApic {}
+
+impl LocalApic {
+    fn map(phys: PhysAddr) -> Result<LocalApic, ApicError> {
+        let mapping = map_mmio(phys, 0x400, CacheMode::Uncached)?;
+        Ok(LocalApic {
+            registers: mapping.as_ptr(),
+            _mapping: mapping,
+        })
+    }
+
+    pub fn id(&self) -> u8 {
+        let id = unsafe { ReadWriteRegister::read(addr_of!((*self.registers).id)) };
+        (id >> 24) as u8
+    }
+
+    fn enable(&self) {
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+        }
+        let registers = self.registers;
+        unsafe {
+            // Accept every priority.
+            ReadWriteRegister::write(addr_of_mut!((*registers).task_priority), 0);
+            // Bit 8 is the software enable.
+            ReadWriteRegister::write(
+                addr_of_mut!((*registers).spurious_interrupt_vector),
+                0x100 | u32::from(SPURIOUS_VECTOR),
+            );
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { WriteOnlyRegister::write(addr_of_mut!((*self.registers).end_of_interrupt), 0) }
+    }
+}
+
+/// Register window of one I/O APIC.
+pub struct IoApic {
+    registers: *mut IoApicRegisters,
+    gsi_base: u32,
+    entries: u32,
+    _mapping: MmioMapping,
+}
+
+unsafe impl Send for IoApic {}
+
+const IOAPIC_VERSION: u8 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u8 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+impl IoApic {
+    fn map(phys: PhysAddr, gsi_base: u32) -> Result<IoApic, ApicError> {
+        let mapping = map_mmio(phys, 0x20, CacheMode::Uncached)?;
+        let mut io_apic = IoApic {
+            registers: mapping.as_ptr(),
+            gsi_base,
+            entries: 0,
+            _mapping: mapping,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&mut self, register: u8) -> u32 {
+        unsafe { (*self.registers).read(register) }
+    }
+
+    fn write(&mut self, register: u8, value: u32) {
+        unsafe { (*self.registers).write(register, value) }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn set_entry(&mut self, gsi: u32, entry: u64) {
+        let register = (u32::from(IOAPIC_REDIRECTION_TABLE) + 2 * (gsi - self.gsi_base)) as u8;
+        // Mask first so the entry is never live half written.
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn mask_all(&mut self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.set_entry(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
+
+/// Where an ISA IRQ ends up on the I/O APIC, after interrupt source overrides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
 }
 
-impl Apic {
-    fn new(physical_memory_offset: VirtAddr) -> Apic {
-        // TODO: There's a MSR that controls the offset but I don't see a reason
-        // to contol that here.
+impl IsaRoute {
+    /// ISA interrupts are edge triggered and active high unless overridden.
+    pub fn for_irq(irq: u8, overrides: &[InterruptSourceOverride]) -> IsaRoute {
+        match overrides.iter().find(|o| o.isa_source == irq) {
+            Some(o) => IsaRoute {
+                gsi: o.global_system_interrupt,
+                active_low: matches!(o.polarity, Polarity::ActiveLow),
+                level_triggered: matches!(o.trigger_mode, TriggerMode::Level),
+            },
+            None => IsaRoute {
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            },
+        }
+    }
 
-        todo!()
+    /// Redirection table entry delivering the IRQ as `vector` to the APIC `destination`.
+    fn entry(&self, vector: u8, destination: u8) -> u64 {
+        let mut entry = u64::from(vector) | (u64::from(destination) << 56);
+        if self.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            entry |= REDIRECTION_LEVEL;
+        }
+        entry
+    }
+}
 
+/// Switch interrupt delivery from the 8259s to the APICs described by `model`.
+///
+/// ISA IRQ `n` keeps arriving on vector `PIC1_OFFSET + n`. Lines with a handler registered
+/// are unmasked, the rest when `dispatch::register_irq` is called for them. On error nothing
+/// is changed and the PICs stay in use.
+pub fn init(model: &InterruptModel) -> Result<(), ApicError> {
+    let apic = match model {
+        InterruptModel::Apic(apic) => apic,
+        _ => return Err(ApicError::NotPresent),
+    };
+    if apic.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
     }
 
-    fn enable(&mut self) {
-        self.registers.spurious_interrupt_vector.write(
-            0b_00000000_00000001_11111111_11111111
-        );
+    let local_apic = LocalApic::map(PhysAddr::new(apic.local_apic_address))?;
+    let mut io_apics = Vec::with_capacity(apic.io_apics.len());
+    for io_apic in apic.io_apics.iter() {
+        io_apics.push(IoApic::map(
+            PhysAddr::new(u64::from(io_apic.address)),
+            io_apic.global_system_interrupt_base,
+        )?);
     }
+
+    let mut routes = [IsaRoute::for_irq(0, &[]); 16];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        *route = IsaRoute::for_irq(irq as u8, &apic.interrupt_source_overrides);
+    }
+
+    interrupts::without_interrupts(|| {
+        unsafe { pic_8256::mask_all() };
+        local_apic.enable();
+        ISA_ROUTES.init_once(|| (routes, local_apic.id()));
+        for io_apic in io_apics.iter_mut() {
+            io_apic.mask_all();
+        }
+        *IO_APICS.lock() = io_apics;
+        LOCAL_APIC.init_once(|| local_apic);
+
+        for irq in 0..16 {
+            if dispatch::has_handlers(pic_8256::PIC1_OFFSET + irq) {
+                set_isa_irq_masked(irq, false);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Mask or unmask ISA IRQ `irq` on the I/O APIC. Does nothing before `init`.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) {
+    let (routes, destination) = match ISA_ROUTES.get() {
+        Some(routes) => routes,
+        None => return,
+    };
+    if irq == ISA_CASCADE_IRQ {
+        return;
+    }
+    let route = routes[irq as usize];
+    let mut entry = route.entry(pic_8256::PIC1_OFFSET + irq, *destination);
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    interrupts::without_interrupts(|| {
+        let mut io_apics = IO_APICS.lock();
+        if let Some(io_apic) = io_apics.iter_mut().find(|io| io.handles(route.gsi)) {
+            io_apic.set_entry(route.gsi, entry);
+        }
+    });
+}
+
+/// Where ISA IRQ `irq` is wired, once `init` has succeeded.
+pub fn isa_route(irq: u8) -> Option<IsaRoute> {
+    ISA_ROUTES.get().map(|(routes, _)| routes[irq as usize])
+}
+
+/// Deliver global system interrupt `gsi` as `vector`, e.g. for devices that aren't on an ISA
+/// line. `route` gives the polarity and trigger mode, its `gsi` is ignored.
+pub fn route_gsi(gsi: u32, route: IsaRoute, vector: u8, masked: bool) -> Result<(), ApicError> {
+    let destination = match ISA_ROUTES.get() {
+        Some((_, destination)) => *destination,
+        None => return Err(ApicError::NotPresent),
+    };
+    let mut entry = route.entry(vector, destination);
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    interrupts::without_interrupts(|| {
+        let mut io_apics = IO_APICS.lock();
+        match io_apics.iter_mut().find(|io| io.handles(gsi)) {
+            Some(io_apic) => {
+                io_apic.set_entry(gsi, entry);
+                Ok(())
+            }
+            None => Err(ApicError::UnknownGsi(gsi)),
+        }
+    })
+}
+
+/// Whether `gsi` is an input of one of the I/O APICs.
+pub fn has_gsi(gsi: u32) -> bool {
+    interrupts::without_interrupts(|| IO_APICS.lock().iter().any(|io| io.handles(gsi)))
+}
+
+/// The local APIC, once `init` has succeeded.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
 }
 
 #[cfg(test)]
@@ -188,6 +486,18 @@ fn check_io_apic_register_alignment() {
     assert_eq!(0x10, offset_of!(IoApicRegisters => data).get_byte_offset(), "data");
 }
 
-
-
-}
\ No newline at end of file
+#[test_case]
+fn isa_routes_follow_overrides() {
+    let overrides = [InterruptSourceOverride {
+        isa_source: 0,
+        global_system_interrupt: 2,
+        polarity: Polarity::SameAsBus,
+        trigger_mode: TriggerMode::SameAsBus,
+    }];
+    let timer = IsaRoute::for_irq(0, &overrides);
+    assert_eq!(timer.gsi, 2);
+    assert!(!timer.active_low && !timer.level_triggered);
+    assert_eq!(IsaRoute::for_irq(1, &overrides).gsi, 1);
+    assert_eq!(timer.entry(32, 3), 32 | (3 << 56));
+}
+}
@@ -0,0 +1,309 @@
+// src/irq/dispatch.rs
+
+//! Runtime registration of interrupt handlers.
+//!
+//! Every vector from 32 up gets a generic stub in the IDT which calls the handlers
+//! registered for it and then sends the end of interrupt. Up to `MAX_SHARED` handlers can
+//! share a vector, for shared PCI lines, and all of them run on every interrupt since more
+//! than one device may have raised it. Handlers are plain functions with a `usize` of
+//! context so registering never allocates.
+//!
+//! Handlers only acknowledge their device and queue the rest with
+//! `crate::tasks::deferred::defer`. See there for why.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use super::stats::{self, SpuriousSource};
+use super::{apic, controller, end_of_interrupt, pic_8256, InterruptController};
+
+/// First vector available to devices, everything below is a CPU exception.
+pub const FIRST_DEVICE_VECTOR: u8 = 32;
+/// Vectors handed out by `allocate_vector`, above the ISA lines.
+const DYNAMIC_VECTORS: core::ops::Range<u8> = 0x40..0xf0;
+pub const MAX_SHARED: usize = 4;
+
+const VECTOR_COUNT: usize = 256 - FIRST_DEVICE_VECTOR as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt came from this handler's device.
+    Handled,
+    /// Not ours.
+    NotHandled,
+}
+
+pub type Handler = fn(usize) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Exceptions and the APIC spurious vector can't take handlers.
+    ReservedVector,
+    /// `MAX_SHARED` handlers are already registered for the vector.
+    LineFull,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registration {
+    id: u64,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+}
+
+type Slots = [Option<Registration>; MAX_SHARED];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: RwLock<Slots> = RwLock::new([None; MAX_SHARED]);
+static HANDLERS: [RwLock<Slots>; VECTOR_COUNT] = [NO_HANDLERS; VECTOR_COUNT];
+/// Bit per vector, set if handed out by `allocate_vector`.
+static ALLOCATED_VECTORS: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+fn slots(vector: u8) -> Result<&'static RwLock<Slots>, RegisterError> {
+    if vector < FIRST_DEVICE_VECTOR || vector == apic::SPURIOUS_VECTOR {
+        return Err(RegisterError::ReservedVector);
+    }
+    Ok(&HANDLERS[(vector - FIRST_DEVICE_VECTOR) as usize])
+}
+
+/// Call `handler(data)` whenever `vector` fires. `name` shows up in diagnostics.
+pub fn register_handler(
+    vector: u8,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, RegisterError> {
+    let slots = slots(vector)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // Handlers take the read lock, so never hold the write lock with interrupts on.
+    interrupts::without_interrupts(|| {
+        let mut slots = slots.write();
+        let free = slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegisterError::LineFull)?;
+        *free = Some(Registration {
+            id,
+            name,
+            handler,
+            data,
+        });
+        Ok(HandlerId { vector, id })
+    })
+}
+
+/// Register a handler for ISA IRQ line `irq` and unmask the line.
+pub fn register_irq(
+    irq: u8,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, RegisterError> {
+    assert!(irq < 16, "ISA IRQ {} out of range", irq);
+    let id = register_handler(pic_8256::PIC1_OFFSET + irq, name, handler, data)?;
+    if controller() == InterruptController::Apic {
+        apic::set_isa_irq_masked(irq, false);
+    }
+    Ok(id)
+}
+
+/// Remove a handler. The line is left unmasked, other devices may still be on it.
+pub fn unregister(id: HandlerId) {
+    if let Ok(slots) = slots(id.vector) {
+        interrupts::without_interrupts(|| {
+            for slot in slots.write().iter_mut() {
+                if matches!(slot, Some(registration) if registration.id == id.id) {
+                    *slot = None;
+                }
+            }
+        });
+    }
+}
+
+/// Whether anything is registered for `vector`.
+pub fn has_handlers(vector: u8) -> bool {
+    match slots(vector) {
+        Ok(slots) => slots.read().iter().any(Option::is_some),
+        Err(_) => false,
+    }
+}
+
+/// Call `f` with the name of every handler registered for `vector`.
+pub fn handler_names(vector: u8, mut f: impl FnMut(&'static str)) {
+    if let Ok(slots) = slots(vector) {
+        for registration in slots.read().iter().flatten() {
+            f(registration.name);
+        }
+    }
+}
+
+/// Reserve a vector nobody else uses, e.g. for an MSI.
+pub fn allocate_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    let vector = DYNAMIC_VECTORS.clone().find(|&vector| {
+        allocated[vector as usize / 64] & (1 << (vector % 64)) == 0 && !has_handlers(vector)
+    })?;
+    allocated[vector as usize / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Give back a vector from `allocate_vector`. Its handlers should be unregistered first.
+pub fn free_vector(vector: u8) {
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    assert!(
+        allocated[vector as usize / 64] & (1 << (vector % 64)) != 0,
+        "vector {:#x} freed twice",
+        vector
+    );
+    allocated[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
+/// MSI address and data to program into a device so it raises `vector` on this CPU.
+///
+/// Needs the APIC, the PIC has no way to receive MSIs.
+pub fn msi_message(vector: u8) -> Option<(u64, u32)> {
+    let local_apic = apic::local_apic()?;
+    let address = 0xfee0_0000 | (u64::from(local_apic.id()) << 12);
+    // Fixed delivery, edge triggered.
+    Some((address, u32::from(vector)))
+}
+
+/// Called by the stubs with interrupts disabled.
+fn dispatch(vector: u8) {
+    if is_spurious_pic_irq(vector) {
+        return;
+    }
+    stats::record(vector);
+    if !run_handlers(vector) {
+        stats::record_unhandled(vector);
+    }
+    end_of_interrupt(vector);
+}
+
+/// Run every handler registered for `vector`, without acknowledging the interrupt. Returns
+/// whether any handled it.
+///
+/// Stopping at the first would lose an edge from a second device on a shared line, or cost a
+/// level triggered line another round trip.
+fn run_handlers(vector: u8) -> bool {
+    let index = (vector - FIRST_DEVICE_VECTOR) as usize;
+    let mut handled = false;
+    // The write lock is only taken with interrupts off, so this can only fail if a handler
+    // registers from inside an interrupt.
+    if let Some(slots) = HANDLERS[index].try_read() {
+        for registration in slots.iter().flatten() {
+            if (registration.handler)(registration.data) == IrqReturn::Handled {
+                handled = true;
+            }
+        }
+    }
+    handled
+}
+
+/// The PICs raise IRQ 7 or 15 when a request goes away before it's acknowledged. Those
+/// show up without their bit set in the in-service register and must not get an EOI from
+/// the PIC that raised them; the master still needs one for the cascade on IRQ 15.
+fn is_spurious_pic_irq(vector: u8) -> bool {
+    if controller() != InterruptController::Pic {
+        return false;
+    }
+    let irq = vector.wrapping_sub(pic_8256::PIC1_OFFSET);
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    if pic_8256::in_service() & (1 << irq) != 0 {
+        return false;
+    }
+    if irq == 7 {
+        stats::record_spurious(SpuriousSource::Pic1);
+    } else {
+        stats::record_spurious(SpuriousSource::Pic2);
+        unsafe { pic_8256::end_of_interrupt_master() };
+    }
+    true
+}
+
+extern "x86-interrupt" fn stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+macro_rules! stubs {
+    ($($vector:literal),* $(,)?) => {
+        [$(stub::<$vector> as HandlerFunc),*]
+    };
+}
+
+static STUBS: [HandlerFunc; VECTOR_COUNT - 1] = stubs![
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+    80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+    96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+    112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127,
+    128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143,
+    144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159,
+    160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175,
+    176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191,
+    192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207,
+    208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223,
+    224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239,
+    240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254,
+];
+
+/// Point every device vector except the APIC spurious one at its dispatch stub.
+pub(super) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    for (i, &stub) in STUBS.iter().enumerate() {
+        idt[FIRST_DEVICE_VECTOR as usize + i].set_handler_fn(stub);
+    }
+}
+
+#[test_case]
+fn every_registered_handler_is_called() {
+    use core::sync::atomic::AtomicUsize;
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn not_mine(_: usize) -> IrqReturn {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::NotHandled
+    }
+    fn mine(data: usize) -> IrqReturn {
+        CALLS.fetch_add(data, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+
+    let vector = allocate_vector().expect("free vector");
+    let first = register_handler(vector, "not mine", not_mine, 0).unwrap();
+    let second = register_handler(vector, "mine", mine, 10).unwrap();
+    let third = register_handler(vector, "also mine", mine, 100).unwrap();
+    assert!(has_handlers(vector));
+    assert_eq!(
+        register_handler(apic::SPURIOUS_VECTOR, "spurious", mine, 0),
+        Err(RegisterError::ReservedVector)
+    );
+
+    // No interrupt is in service, so nothing may be acknowledged.
+    let handled = x86_64::instructions::interrupts::without_interrupts(|| run_handlers(vector));
+    assert!(handled);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 111);
+
+    unregister(first);
+    unregister(second);
+    unregister(third);
+    assert!(!has_handlers(vector));
+    free_vector(vector);
+}
@@ -0,0 +1,318 @@
+// src/irq/exceptions.rs
+
+//! Handlers for the architectural exceptions, vectors 0 to 31.
+//!
+//! Apart from breakpoints, debug traps and page faults that can be resolved, every exception
+//! ends up in `oops::oops`.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use super::oops::{oops, DecodedPageFault};
+use super::stats;
+use crate::gdt;
+use crate::memory_manager::fault::{self, FaultResolution};
+use crate::prelude::*;
+use crate::tasks::deferred;
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+}
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point exception",
+    "alignment check",
+    "machine check",
+    "SIMD floating point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved",
+];
+
+/// Name of exception `vector`, `None` for device vectors.
+pub fn exception_name(vector: u8) -> Option<&'static str> {
+    EXCEPTION_NAMES.get(vector as usize).copied()
+}
+
+/// Error code pushed by exceptions that refer to a segment selector or IDT entry.
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "error code 0");
+        }
+        let table = match (code >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} entry {:#x}", table, (code >> 3) & 0x1fff)?;
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        write!(f, " (error code {:#x})", code)
+    }
+}
+
+macro_rules! fatal {
+    ($handler:ident, $vector:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            stats::record($vector);
+            oops(format_args!("{}", EXCEPTION_NAMES[$vector]), None, &stack_frame);
+        }
+    };
+}
+
+macro_rules! fatal_with_selector {
+    ($handler:ident, $vector:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            stats::record($vector);
+            oops(
+                format_args!("{}", EXCEPTION_NAMES[$vector]),
+                Some(format_args!("{}", SelectorErrorCode(error_code))),
+                &stack_frame,
+            );
+        }
+    };
+}
+
+fatal!(divide_error_handler, 0);
+fatal!(nmi_handler, 2);
+fatal!(overflow_handler, 4);
+fatal!(bound_range_exceeded_handler, 5);
+fatal!(invalid_opcode_handler, 6);
+fatal!(device_not_available_handler, 7);
+fatal!(x87_floating_point_handler, 16);
+fatal!(simd_floating_point_handler, 19);
+fatal!(virtualization_handler, 20);
+fatal_with_selector!(invalid_tss_handler, 10);
+fatal_with_selector!(segment_not_present_handler, 11);
+fatal_with_selector!(stack_segment_fault_handler, 12);
+fatal_with_selector!(general_protection_fault_handler, 13);
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    stats::record(17);
+    oops(
+        format_args!("{}", EXCEPTION_NAMES[17]),
+        Some(format_args!("error code {:#x}", error_code)),
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(30);
+    oops(
+        format_args!("{}", EXCEPTION_NAMES[30]),
+        Some(format_args!("error code {:#x}", error_code)),
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    stats::record(18);
+    oops(format_args!("{}", EXCEPTION_NAMES[18]), None, &stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    stats::record(1);
+    record_trap(1, &stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    stats::record(3);
+    record_trap(3, &stack_frame);
+}
+
+/// Debug and breakpoint traps kept until their report runs.
+const TRAP_SLOTS: usize = 8;
+
+/// A trap frame saved for `report_trap`. Written from the handler, so no locks.
+struct TrapRecord {
+    sequence: AtomicUsize,
+    vector: AtomicU8,
+    instruction_pointer: AtomicU64,
+    code_segment: AtomicU64,
+    cpu_flags: AtomicU64,
+    stack_pointer: AtomicU64,
+    stack_segment: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_TRAP: TrapRecord = TrapRecord {
+    sequence: AtomicUsize::new(usize::MAX),
+    vector: AtomicU8::new(0),
+    instruction_pointer: AtomicU64::new(0),
+    code_segment: AtomicU64::new(0),
+    cpu_flags: AtomicU64::new(0),
+    stack_pointer: AtomicU64::new(0),
+    stack_segment: AtomicU64::new(0),
+};
+static TRAPS: [TrapRecord; TRAP_SLOTS] = [EMPTY_TRAP; TRAP_SLOTS];
+static NEXT_TRAP: AtomicUsize = AtomicUsize::new(0);
+
+/// Save the frame and report it from task context. The trap may have hit while the code it
+/// interrupted held the console lock, so printing here could deadlock.
+fn record_trap(vector: u8, stack_frame: &InterruptStackFrame) {
+    let sequence = NEXT_TRAP.fetch_add(1, Ordering::Relaxed);
+    let record = &TRAPS[sequence % TRAP_SLOTS];
+    record.vector.store(vector, Ordering::Relaxed);
+    record
+        .instruction_pointer
+        .store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    record
+        .code_segment
+        .store(stack_frame.code_segment, Ordering::Relaxed);
+    record.cpu_flags.store(stack_frame.cpu_flags, Ordering::Relaxed);
+    record
+        .stack_pointer
+        .store(stack_frame.stack_pointer.as_u64(), Ordering::Relaxed);
+    record
+        .stack_segment
+        .store(stack_frame.stack_segment, Ordering::Relaxed);
+    record.sequence.store(sequence, Ordering::Release);
+    // Without the deferred work queue the trap only shows up in the interrupt stats.
+    deferred::defer(report_trap, sequence).ok();
+}
+
+fn report_trap(sequence: usize) {
+    let record = &TRAPS[sequence % TRAP_SLOTS];
+    // Reading with interrupts off keeps another trap from overwriting the slot halfway.
+    let frame = interrupts::without_interrupts(|| {
+        if record.sequence.load(Ordering::Acquire) != sequence {
+            return None;
+        }
+        Some((
+            record.vector.load(Ordering::Relaxed),
+            record.code_segment.load(Ordering::Relaxed),
+            record.instruction_pointer.load(Ordering::Relaxed),
+            record.stack_segment.load(Ordering::Relaxed),
+            record.stack_pointer.load(Ordering::Relaxed),
+            record.cpu_flags.load(Ordering::Relaxed),
+        ))
+    });
+    match frame {
+        Some((vector, cs, rip, ss, rsp, rflags)) => println!(
+            "EXCEPTION: {}\nRIP: {:#x}:{:#018x} RSP: {:#x}:{:#018x} RFLAGS: {:#x}",
+            EXCEPTION_NAMES[usize::from(vector)],
+            cs,
+            rip,
+            ss,
+            rsp,
+            rflags
+        ),
+        None => println!("EXCEPTION: trap frame overwritten before it was reported"),
+    }
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    stats::record(8);
+    // Running off the end of a stack into its guard page faults again while pushing the
+    // page fault frame, so stack overflows usually end up here.
+    let addr = Cr2::read();
+    fault::guard_owner(addr, |owner| match owner {
+        Some(owner) => oops(
+            format_args!("stack overflow in {}", owner),
+            Some(format_args!("guard page hit at {:?}", addr)),
+            &stack_frame,
+        ),
+        None => oops(
+            format_args!("{}", EXCEPTION_NAMES[8]),
+            Some(format_args!("error code {:#x}, last page fault at {:?}", error_code, addr)),
+            &stack_frame,
+        ),
+    })
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    stats::record(14);
+    let addr = Cr2::read();
+    match fault::resolve_page_fault(addr, error_code) {
+        FaultResolution::Resolved => {}
+        FaultResolution::GuardPage => fault::guard_owner(addr, |owner| {
+            oops(
+                format_args!("stack overflow in {}", owner.unwrap_or("<unknown>")),
+                Some(format_args!("guard page hit at {:?}: {}", addr, DecodedPageFault(error_code))),
+                &stack_frame,
+            )
+        }),
+        FaultResolution::NotHandled => oops(
+            format_args!("{} at {:?}", EXCEPTION_NAMES[14], addr),
+            Some(format_args!("{}", DecodedPageFault(error_code))),
+            &stack_frame,
+        ),
+    }
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    // Invoke a breakpoint.
+    x86_64::instructions::interrupts::int3();
+}
@@ -1,41 +1,99 @@
 // src/irq/irq.rs
 
+pub mod apic;
+pub mod dispatch;
+pub mod exceptions;
+pub mod oops;
 pub mod pic_8256;
-// pub mod apic;
+pub mod stats;
 
-use crate::{gdt, halt_loop, tasks::timer};
+use crate::tasks::{deferred, timer};
 use crate::prelude::*;
 use crate::disk::ata;
+use acpi::InterruptModel;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use x86_64::instructions::{port::Port};
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use self::pic_8256::PICS;
 
+pub use self::dispatch::{
+    allocate_vector, free_vector, has_handlers, msi_message, register_handler, register_irq,
+    unregister, HandlerId, IrqReturn, RegisterError,
+};
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-
-        idt[InterruptIndex::PrimaryATA.as_usize()].set_handler_fn(primary_ata_handler);
-        idt[InterruptIndex::SecondaryATA.as_usize()].set_handler_fn(secondary_ata_handler);
+        exceptions::install(&mut idt);
+        dispatch::install_stubs(&mut idt);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_handler);
 
         idt
     };
 }
 
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
 pub fn init() {
     println!("intializing idt");
     IDT.load();
+
+    let builtin: [(InterruptIndex, &'static str, dispatch::Handler); 4] = [
+        (InterruptIndex::Timer, "timer", timer_interrupt_handler),
+        (InterruptIndex::Keyboard, "keyboard", keyboard_interrupt_handler),
+        (InterruptIndex::PrimaryATA, "primary ata", primary_ata_handler),
+        (InterruptIndex::SecondaryATA, "secondary ata", secondary_ata_handler),
+    ];
+    for &(index, name, handler) in builtin.iter() {
+        register_irq(index.irq(), name, handler, 0)
+            .unwrap_or_else(|err| panic!("Failed to register {} handler: {:?}", name, err));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The legacy 8259 pair, set up by `crate::init`.
+    Pic,
+    /// Local APIC and I/O APIC, discovered through ACPI.
+    Apic,
+}
+
+/// Switch to `preferred` if it's available. Returns the controller in use afterwards.
+pub fn select_controller(
+    preferred: InterruptController,
+    interrupt_model: Option<&InterruptModel>,
+) -> InterruptController {
+    if preferred == InterruptController::Apic && controller() == InterruptController::Pic {
+        match interrupt_model.map(apic::init) {
+            Some(Ok(())) => USING_APIC.store(true, Ordering::SeqCst),
+            Some(Err(err)) => println!("APIC unavailable, staying on the PIC: {}", err),
+            None => println!("No ACPI interrupt model, staying on the PIC"),
+        }
+    }
+    controller()
+}
+
+pub fn controller() -> InterruptController {
+    if USING_APIC.load(Ordering::SeqCst) {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    }
+}
+
+/// Acknowledge `vector` on whichever controller delivered it. The dispatch stubs do this
+/// after running the handlers.
+pub fn end_of_interrupt(vector: u8) {
+    match apic::local_apic() {
+        Some(local_apic) if USING_APIC.load(Ordering::Relaxed) => local_apic.end_of_interrupt(),
+        _ => {
+            let mut pics = PICS.lock();
+            if pics.handles_interrupt(vector) {
+                unsafe { pics.notify_end_of_interrupt(vector) };
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -68,84 +126,46 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+    /// ISA IRQ line number.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - pic_8256::PIC1_OFFSET
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: Breakpoint\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!("DOUBLE FAULT. Code {}:\n{:#?}", error_code, stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:?}", stack_frame);
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:?}", stack_frame);
-
-    halt_loop();
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn timer_interrupt_handler(_: usize) -> IrqReturn {
     timer::next_tick();
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // Always take your locks together.
-    let mut pics = PICS.lock();
-
+fn keyboard_interrupt_handler(_: usize) -> IrqReturn {
     let mut port: Port<u8> = Port::new(0x60);
 
     let scancode = unsafe { port.read() };
     crate::tasks::keyboard::add_scancode(scancode);
+    IrqReturn::Handled
+}
 
-    unsafe {
-        pics.notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+fn primary_ata_handler(_: usize) -> IrqReturn {
+    ata::interrupt(ata::BusKind::Primary);
+    deferred::defer(log_ata_interrupt, 0).ok();
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn primary_ata_handler(_stack_frame: InterruptStackFrame) {
-    let mut pics = PICS.lock();
-    println!("primary ata handler");
-    ata::interrupt( ata::BusKind::Primary); 
-    
-    unsafe {
+fn secondary_ata_handler(_: usize) -> IrqReturn {
+    deferred::defer(log_ata_interrupt, 1).ok();
+    IrqReturn::Handled
+}
 
-        pics.notify_end_of_interrupt(InterruptIndex::PrimaryATA.as_u8());
+fn log_ata_interrupt(bus: usize) {
+    match bus {
+        0 => println!("primary ata interrupt"),
+        _ => println!("secondary ata interrupt"),
     }
 }
 
-extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: InterruptStackFrame) {
-    let mut pics = PICS.lock();
-    println!("secondary ata handler");
-    unsafe {
-        pics.notify_end_of_interrupt(InterruptIndex::SecondaryATA.as_u8());
-    }
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious APIC interrupts are never acknowledged.
+    stats::record_spurious(stats::SpuriousSource::Apic);
 }
 
 // PICS
-
-#[test_case]
-fn test_breakpoint_exception() {
-    // Invoke a breakpoint.
-    x86_64::instructions::interrupts::int3();
-}
@@ -0,0 +1,120 @@
+// src/irq/oops.rs
+
+//! Reporting for exceptions the kernel can't recover from.
+
+use core::fmt;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        model_specific::Efer,
+    },
+    structures::{
+        idt::{InterruptStackFrame, PageFaultErrorCode},
+        paging::Translate,
+    },
+    VirtAddr,
+};
+
+use crate::io::{stdout, Write};
+use crate::memory_manager::try_memory_manager;
+
+/// Bytes of code shown from the faulting instruction on.
+const CODE_BYTES: u64 = 16;
+
+/// Print everything we know about the fault and panic.
+///
+/// The panic handler takes it from there, so a fault in a test still fails the test run.
+pub fn oops(
+    title: fmt::Arguments<'_>,
+    details: Option<fmt::Arguments<'_>>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    {
+        // Whatever we interrupted may be holding stdout.
+        let stdout = stdout();
+        let mut out = unsafe { stdout.break_lock() };
+        writeln!(out, "\nKERNEL OOPS: {}", title).ok();
+        if let Some(details) = details {
+            writeln!(out, "{}", details).ok();
+        }
+        writeln!(
+            out,
+            "RIP: {:#x}:{:#018x} RSP: {:#x}:{:#018x} RFLAGS: {:#x}",
+            stack_frame.code_segment,
+            stack_frame.instruction_pointer.as_u64(),
+            stack_frame.stack_segment,
+            stack_frame.stack_pointer.as_u64(),
+            stack_frame.cpu_flags
+        )
+        .ok();
+        writeln!(out, "Code: {}", CodeBytes(stack_frame.instruction_pointer)).ok();
+        let (level_4_table, cr3_flags) = Cr3::read();
+        writeln!(
+            out,
+            "CR0: {:?}\nCR2: {:#018x}\nCR3: {:#018x} {:?}\nCR4: {:?}\nEFER: {:?}",
+            Cr0::read(),
+            Cr2::read().as_u64(),
+            level_4_table.start_address().as_u64(),
+            cr3_flags,
+            Cr4::read(),
+            Efer::read()
+        )
+        .ok();
+    }
+    panic!("kernel oops: {}", title);
+}
+
+/// Human readable description of a page fault error code.
+pub struct DecodedPageFault(pub PageFaultErrorCode);
+
+impl fmt::Display for DecodedPageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {}: {}", mode, access, cause)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in page table")?;
+        }
+        write!(f, " (error code {:#x})", code.bits())
+    }
+}
+
+/// The bytes at an instruction pointer, if they can be read without faulting again.
+struct CodeBytes(VirtAddr);
+
+impl fmt::Display for CodeBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.0;
+        let end = start + (CODE_BYTES - 1);
+        let mapped = match try_memory_manager().and_then(|mm| mm.try_lock()) {
+            Some(mm) => {
+                mm.mapper.translate_addr(start).is_some() && mm.mapper.translate_addr(end).is_some()
+            }
+            None => false,
+        };
+        if !mapped {
+            return write!(f, "<unavailable>");
+        }
+        for i in 0..CODE_BYTES {
+            let byte = unsafe { (start + i).as_ptr::<u8>().read_volatile() };
+            write!(f, "{:02x} ", byte)?;
+        }
+        Ok(())
+    }
+}
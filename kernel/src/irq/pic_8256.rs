@@ -13,10 +13,39 @@ pub static PICS: Mutex<ChainedPics> = Mutex::const_new(spin::Mutex::new(()), uns
 pub unsafe fn disable() {
     // Well uhhh probably don't want interrups while PIC's are being turned off.
     interrupts::disable();
+    mask_all();
+}
 
+/// Mask every IRQ line. The PICs stay remapped, so spurious IRQs still land on our vectors.
+pub unsafe fn mask_all() {
     let mut port2: Port<u8> = Port::new(0xa1);
     let mut port1: Port<u8> = Port::new(0x21);
 
     port2.write(0xff);
     port1.write(0xff);
 }
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC2_COMMAND: u16 = 0xa0;
+/// OCW3: the next read of the command port returns the in-service register.
+const READ_ISR: u8 = 0x0b;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// In-service registers of both PICs, the slave's in the high byte.
+///
+/// A bit is set for every IRQ the PICs have delivered and not yet seen an EOI for.
+pub fn in_service() -> u16 {
+    let mut pic1: Port<u8> = Port::new(PIC1_COMMAND);
+    let mut pic2: Port<u8> = Port::new(PIC2_COMMAND);
+    unsafe {
+        pic1.write(READ_ISR);
+        pic2.write(READ_ISR);
+        u16::from(pic1.read()) | (u16::from(pic2.read()) << 8)
+    }
+}
+
+/// Send an EOI to the master only. For spurious IRQs from the slave, where the master did
+/// deliver the cascade interrupt but the slave has nothing in service.
+pub unsafe fn end_of_interrupt_master() {
+    Port::<u8>::new(PIC1_COMMAND).write(END_OF_INTERRUPT);
+}
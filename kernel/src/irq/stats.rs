@@ -0,0 +1,138 @@
+// src/irq/stats.rs
+
+//! Interrupt counters, like Linux's /proc/interrupts.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{dispatch, exceptions};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; 256] = [ZERO; 256];
+/// Interrupts no registered handler claimed.
+static UNHANDLED: [AtomicU64; 256] = [ZERO; 256];
+static SPURIOUS_PIC1: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_PIC2: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_APIC: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpuriousSource {
+    /// IRQ 7 with nothing in service on the master PIC.
+    Pic1,
+    /// IRQ 15 with nothing in service on the slave PIC.
+    Pic2,
+    /// The local APIC's spurious vector.
+    Apic,
+}
+
+pub(super) fn record(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_unhandled(vector: u8) {
+    UNHANDLED[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_spurious(source: SpuriousSource) {
+    let counter = match source {
+        SpuriousSource::Pic1 => &SPURIOUS_PIC1,
+        SpuriousSource::Pic2 => &SPURIOUS_PIC2,
+        SpuriousSource::Apic => &SPURIOUS_APIC,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Times `vector` was taken so far. Spurious interrupts aren't counted here.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Point in time copy of the interrupt counters.
+#[derive(Clone)]
+pub struct InterruptStats {
+    pub counts: [u64; 256],
+    pub unhandled: [u64; 256],
+    pub spurious_pic1: u64,
+    pub spurious_pic2: u64,
+    pub spurious_apic: u64,
+}
+
+pub fn snapshot() -> InterruptStats {
+    let mut stats = InterruptStats {
+        counts: [0; 256],
+        unhandled: [0; 256],
+        spurious_pic1: SPURIOUS_PIC1.load(Ordering::Relaxed),
+        spurious_pic2: SPURIOUS_PIC2.load(Ordering::Relaxed),
+        spurious_apic: SPURIOUS_APIC.load(Ordering::Relaxed),
+    };
+    for vector in 0..256 {
+        stats.counts[vector] = COUNTS[vector].load(Ordering::Relaxed);
+        stats.unhandled[vector] = UNHANDLED[vector].load(Ordering::Relaxed);
+    }
+    stats
+}
+
+impl InterruptStats {
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn spurious(&self) -> u64 {
+        self.spurious_pic1 + self.spurious_pic2 + self.spurious_apic
+    }
+}
+
+impl fmt::Debug for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterruptStats")
+            .field("total", &self.total())
+            .field("spurious", &self.spurious())
+            .finish()
+    }
+}
+
+/// One line per vector that fired: vector, count, unhandled count and who handles it.
+impl fmt::Display for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "vector      count  unhandled  handlers")?;
+        for (vector, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            write!(f, "{:>6} {:>10} {:>10} ", vector, count, self.unhandled[vector])?;
+            let vector = vector as u8;
+            if let Some(name) = exceptions::exception_name(vector) {
+                write!(f, " {}", name)?;
+            }
+            let mut result = Ok(());
+            dispatch::handler_names(vector, |name| {
+                if result.is_ok() {
+                    result = write!(f, " {}", name);
+                }
+            });
+            result?;
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "   SPU pic1 {} pic2 {} apic {}",
+            self.spurious_pic1, self.spurious_pic2, self.spurious_apic
+        )
+    }
+}
+
+#[test_case]
+fn counts_show_up_in_snapshots() {
+    let before = snapshot();
+    record(0xee);
+    record(0xee);
+    record_unhandled(0xee);
+    record_spurious(SpuriousSource::Apic);
+    let after = snapshot();
+    assert_eq!(after.counts[0xee] - before.counts[0xee], 2);
+    assert_eq!(after.unhandled[0xee] - before.unhandled[0xee], 1);
+    assert_eq!(after.spurious_apic - before.spurious_apic, 1);
+}
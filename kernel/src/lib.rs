@@ -34,6 +34,8 @@ pub mod uart;
 pub mod acpi;
 pub mod memory_manager;
 pub mod pci;
+pub mod pit;
+pub mod time;
 
 pub fn init() {
     io::stdio_init();    
@@ -43,6 +45,10 @@ pub fn init() {
     unsafe { irq::pic_8256::PICS.lock().initialize() };
     println!(" OK");
 
+    print!("Calibrating clocks...");
+    time::init();
+    println!(" using {}", time::clock_source().name());
+
     print!("Enabling interrupts...");
     x86_64::instructions::interrupts::enable();
     println!(" OK");
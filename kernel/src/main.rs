@@ -9,9 +9,8 @@
 
 extern crate alloc;
 
-use alloc::{format, sync::Arc};
+use alloc::format;
 use alloc::prelude::v1::*;
-use spin::lock_api::Mutex;
 
 use core::panic::PanicInfo;
 
@@ -24,12 +23,15 @@ use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg64;
 use x86_64::VirtAddr;
 
-use dumb_os::{allocator::HEAP_SIZE, memory_manager::MemoryManager};
-use dumb_os::memory::BootInfoBumpAllocator;
+use dumb_os::{allocator::HEAP_INITIAL_SIZE, memory_manager::{self, MemoryManager}};
+use dumb_os::irq::{self, InterruptController};
+use dumb_os::memory::BitmapFrameAllocator;
+use dumb_os::tasks::deferred;
 use dumb_os::tasks::executor::Executor;
 use dumb_os::tasks::keyboard::print_keypresses;
 use dumb_os::tasks::timer;
-use dumb_os::tasks::Task;
+use dumb_os::tasks::{Priority, Task};
+use dumb_os::time::{self, Duration, TickSource};
 use dumb_os::{
     allocator,
     tasks::{executor::spawn, timer::sleep},
@@ -86,8 +88,13 @@ fn kernel_main(bootinfo: &'static mut BootInfo) -> ! {
     println!(" OK");
 
     print!("Initializing frame allocator");
-    let mut frame_allocator = unsafe { BootInfoBumpAllocator::init(bootinfo) };
-    println!(" OK");
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&bootinfo.memory_regions, physical_memory_offset) };
+    println!(
+        " OK ({} of {} frames free)",
+        frame_allocator.free_frames(),
+        frame_allocator.usable_frames()
+    );
 
     print!("Initializing heap");
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap allocation failed");
@@ -96,32 +103,39 @@ fn kernel_main(bootinfo: &'static mut BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    let memory_manager = Arc::new(Mutex::new(MemoryManager {
+    memory_manager::init(MemoryManager {
         mapper,
         frame_allocator,
-    }));
+    });
 
-    let acpi = dumb_os::acpi::init(bootinfo, memory_manager.clone())
+    let acpi = dumb_os::acpi::init(bootinfo)
         .unwrap_or_else(|err| panic!("Failed to inialized acpi: {:?}", err));
 
     println!("{:#?}", acpi);
 
-    let mut executor = Executor::new();
+    let controller = irq::select_controller(InterruptController::Apic, acpi.interrupt_model());
+    println!("Using {:?} interrupt controller", controller);
+
+    match time::hpet::init(acpi.hpet_address()) {
+        Ok(hpet) => println!("HPET at {} Hz", hpet.frequency()),
+        Err(err) => println!("HPET unavailable: {}", err),
+    }
+    let tick_source = time::select_tick_source(TickSource::Hpet);
+    println!("Timer ticks from {:?}, clock source {}", tick_source, time::clock_source().name());
 
-    let (timer_task, _timer_handle) = unsafe { timer::init() };
+    let mut executor = Executor::new();
 
-    executor.spawn_task(timer_task).unwrap();
-    executor
-        .spawn_task(Task::new(print_keypresses(), "print keypresses"))
-        .unwrap();
-    // executor.spawn_task(Task::new(disk_main(), "disk main")).unwrap();
+    executor.spawn_task(timer::init());
+    executor.spawn_task(deferred::init());
+    executor.spawn_task(Task::new(print_keypresses(), "print keypresses"));
+    // executor.spawn_task(Task::new(disk_main(), "disk main"));
 
-    executor
-        .spawn_task(Task::new(example_task::<Pcg64>(rng.gen()), "example task"))
-        .unwrap();
+    executor.spawn_task(
+        Task::new(example_task::<Pcg64>(rng.gen()), "example task")
+            .with_priority(Priority::Background),
+    );
     // executor
-    // .spawn_task(Task::new(example_timer(rng.gen() ), "example timer"))
-    // .unwrap();
+    // .spawn_task(Task::new(example_timer(rng.gen() ), "example timer"));
 
     executor.run()
 }
@@ -141,10 +155,10 @@ fn check_bootinfo(bootinfo: &BootInfo) -> () {
             usuable_bytes += region.end - region.start;
         }
     }
-    if usuable_bytes < HEAP_SIZE {
+    if usuable_bytes < HEAP_INITIAL_SIZE {
         panic!(
             "Not enough usuable memory. Require {}, have {}",
-            HEAP_SIZE, usuable_bytes
+            HEAP_INITIAL_SIZE, usuable_bytes
         );
     }
     println!("Have {} bytes of memory", usuable_bytes)
@@ -174,8 +188,8 @@ async fn example_timer(seed: <Pcg64 as SeedableRng>::Seed) {
 }
 
 async fn wait_and_print(i: u64) {
-    sleep(i).await;
-    println!("Waited {} ticks", i);
+    sleep(Duration::from_millis(i)).await;
+    println!("Waited {} ms", i);
 }
 
 /// This function is called on panic.
@@ -0,0 +1,364 @@
+// src/memory/frame_allocator.rs
+
+use core::{fmt, ops::Range, slice};
+
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+/// One bit per frame. A set bit means the frame is in use.
+pub(crate) struct Bitmap<'a> {
+    words: &'a mut [u64],
+    len: usize,
+}
+
+impl<'a> Bitmap<'a> {
+    /// Wraps `words` as a bitmap of `len` bits. Bits past `len` are never handed out.
+    pub(crate) fn new(words: &'a mut [u64], len: usize) -> Bitmap<'a> {
+        assert!(words.len() * 64 >= len, "bitmap storage too small");
+        Bitmap { words, len }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn get(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub(crate) fn set(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    pub(crate) fn clear(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    pub(crate) fn fill(&mut self, value: bool) {
+        let word = if value { !0 } else { 0 };
+        for w in self.words.iter_mut() {
+            *w = word;
+        }
+    }
+
+    /// Find the first clear bit in `range`.
+    pub(crate) fn find_clear(&self, range: Range<usize>) -> Option<usize> {
+        let end = range.end.min(self.len);
+        let mut index = range.start;
+        while index < end {
+            let word = self.words[index / 64] | ((1 << (index % 64)) - 1);
+            if word == !0 {
+                // Skip to the next word.
+                index = (index / 64 + 1) * 64;
+                continue;
+            }
+            let found = (index / 64) * 64 + word.trailing_ones() as usize;
+            return if found < end { Some(found) } else { None };
+        }
+        None
+    }
+
+    /// Find `count` consecutive clear bits in `range` starting at a multiple of `align`.
+    pub(crate) fn find_clear_run(
+        &self,
+        count: usize,
+        align: usize,
+        range: Range<usize>,
+    ) -> Option<usize> {
+        self.find_clear_run_within(count, align, None, range)
+    }
+
+    /// Like `find_clear_run`, but the run may not cross a multiple of `boundary`.
+    pub(crate) fn find_clear_run_within(
+        &self,
+        count: usize,
+        align: usize,
+        boundary: Option<usize>,
+        range: Range<usize>,
+    ) -> Option<usize> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if let Some(boundary) = boundary {
+            assert!(boundary.is_power_of_two(), "boundary must be a power of two");
+            if count > boundary {
+                return None;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        let end = range.end.min(self.len);
+        let mut start = align_up(range.start, align);
+
+        'candidates: while start + count <= end {
+            if let Some(boundary) = boundary {
+                if start / boundary != (start + count - 1) / boundary {
+                    start = align_up(align_up(start + 1, boundary), align);
+                    continue;
+                }
+            }
+            if count == 1 || align == 1 {
+                // Jump straight to the next free bit, then re-align.
+                match self.find_clear(start..end) {
+                    Some(free) if free == start => {}
+                    Some(free) => {
+                        start = align_up(free, align);
+                        continue;
+                    }
+                    None => return None,
+                }
+            }
+            for index in start..start + count {
+                if self.get(index) {
+                    start = align_up(index + 1, align);
+                    continue 'candidates;
+                }
+            }
+            return Some(start);
+        }
+        None
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Physical frame allocator backed by a bitmap covering all usable memory.
+///
+/// The bitmap is stored in the first usable region large enough to hold it and is
+/// accessed through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: Bitmap<'static>,
+    usable_frames: usize,
+    free_frames: usize,
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build the allocator from the bootloader memory map.
+    ///
+    /// # Safety
+    /// `regions` must describe the real memory map and all physical memory must be mapped
+    /// at `physical_memory_offset`. Frames in usable regions must not be in use.
+    pub unsafe fn init(
+        regions: &[MemoryRegion],
+        physical_memory_offset: VirtAddr,
+    ) -> BitmapFrameAllocator {
+        let usable = || {
+            regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+        };
+
+        let max_addr = usable().map(|region| region.end).max().unwrap_or(0);
+        let frame_count = ((max_addr + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let words = (frame_count + 63) / 64;
+        let bitmap_bytes = (words * 8) as u64;
+
+        // Never the null frame, it's reserved separately below.
+        let bitmap_start = usable()
+            .map(|region| (align_up_u64(region.start.max(FRAME_SIZE), FRAME_SIZE), region.end))
+            .find(|&(start, end)| start + bitmap_bytes <= end)
+            .map(|(start, _)| start)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let mut bitmap = Bitmap::new(slice::from_raw_parts_mut(ptr, words), frame_count);
+
+        // Everything is used until a usable region says otherwise.
+        bitmap.fill(true);
+        let mut usable_frames = 0;
+        for region in usable() {
+            let first = (align_up_u64(region.start, FRAME_SIZE) / FRAME_SIZE) as usize;
+            let last = (region.end / FRAME_SIZE) as usize;
+            for index in first..last {
+                bitmap.clear(index);
+                usable_frames += 1;
+            }
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            usable_frames,
+            free_frames: usable_frames,
+            next_free: 0,
+        };
+
+        // Don't hand out the null frame, or the frames holding the bitmap itself.
+        if !allocator.bitmap.get(0) {
+            allocator.mark_used(0);
+        }
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        let bitmap_frames = ((bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.mark_used(index);
+        }
+
+        allocator
+    }
+
+    /// Number of frames in usable memory.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Number of frames currently available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of usable frames that have been handed out (including the bitmap).
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Allocate `count` physically contiguous 4KiB frames whose first frame is aligned to
+    /// `align` frames.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_within(count, align, PhysAddr::new(u64::MAX >> 12), None)
+    }
+
+    /// Like `allocate_contiguous`, but the frames end at or below `limit` and the run doesn't
+    /// cross a multiple of `boundary` frames. For devices that can't address all of memory.
+    pub fn allocate_contiguous_within(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+        boundary: Option<usize>,
+    ) -> Option<PhysFrame> {
+        let end = (limit.as_u64() / FRAME_SIZE).min(self.bitmap.len() as u64) as usize;
+        let start = self
+            .bitmap
+            .find_clear_run_within(count, align, boundary, 0..end)?;
+        for index in start..start + count {
+            self.mark_used(index);
+        }
+        Some(frame_at(start))
+    }
+
+    /// Return `count` contiguous frames starting at `start` to the allocator.
+    ///
+    /// # Safety
+    /// The frames must have come from this allocator and must no longer be in use.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            self.mark_free(index);
+        }
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        debug_assert!(!self.bitmap.get(index));
+        self.bitmap.set(index);
+        self.free_frames -= 1;
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        assert!(
+            self.bitmap.get(index),
+            "double free of frame {:?}",
+            frame_at(index)
+        );
+        self.bitmap.clear(index);
+        self.free_frames += 1;
+        if index < self.next_free {
+            self.next_free = index;
+        }
+    }
+}
+
+fn align_up_u64(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let len = self.bitmap.len();
+        let index = self
+            .bitmap
+            .find_clear(self.next_free..len)
+            .or_else(|| self.bitmap.find_clear(0..self.next_free))?;
+        self.mark_used(index);
+        self.next_free = index + 1;
+        Some(frame_at(index))
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate_contiguous(frame, 1);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_contiguous(
+            PhysFrame::containing_address(frame.start_address()),
+            FRAMES_PER_HUGE_FRAME,
+        );
+    }
+}
+
+impl fmt::Debug for BitmapFrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BitmapFrameAllocator")
+            .field("usable_frames", &self.usable_frames)
+            .field("free_frames", &self.free_frames)
+            .field("bitmap_frames", &self.bitmap.len())
+            .finish()
+    }
+}
+
+#[test_case]
+fn bitmap_find_clear_skips_used_words() {
+    let mut words = [0u64; 4];
+    let mut bitmap = Bitmap::new(&mut words, 200);
+    bitmap.fill(true);
+    bitmap.clear(130);
+    assert_eq!(bitmap.find_clear(0..200), Some(130));
+    assert_eq!(bitmap.find_clear(131..200), None);
+}
+
+#[test_case]
+fn bitmap_find_clear_run_respects_alignment() {
+    let mut words = [0u64; 4];
+    let mut bitmap = Bitmap::new(&mut words, 256);
+    bitmap.set(1);
+    bitmap.set(17);
+    assert_eq!(bitmap.find_clear_run(4, 1, 0..256), Some(2));
+    assert_eq!(bitmap.find_clear_run(16, 16, 0..256), Some(32));
+    assert_eq!(bitmap.find_clear_run(300, 1, 0..256), None);
+}
+
+#[test_case]
+fn bitmap_find_clear_run_respects_boundary() {
+    let mut words = [0u64; 4];
+    let mut bitmap = Bitmap::new(&mut words, 256);
+    bitmap.set(0);
+    // 1..17 would cross 16.
+    assert_eq!(bitmap.find_clear_run_within(16, 1, Some(16), 0..256), Some(16));
+    assert_eq!(bitmap.find_clear_run_within(3, 1, Some(16), 14..256), Some(16));
+    assert_eq!(bitmap.find_clear_run_within(4, 4, Some(8), 0..256), Some(4));
+    assert_eq!(bitmap.find_clear_run_within(17, 1, Some(16), 0..256), None);
+    assert_eq!(bitmap.find_clear_run_within(4, 1, Some(16), 0..18), None);
+}
@@ -0,0 +1,66 @@
+// src/memory/mod.rs
+
+pub mod frame_allocator;
+pub mod protection;
+pub mod walk;
+
+pub use frame_allocator::BitmapFrameAllocator;
+pub use walk::{MappedRange, Mapping, MappingSize, PageTableWalker};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub unsafe fn init(physical_addr_offset: VirtAddr) -> OffsetPageTable<'static> {
+    protection::enable();
+    PHYSICAL_MEMORY_OFFSET.store(physical_addr_offset.as_u64(), Ordering::Relaxed);
+    let level_4_page_table = active_level_4_table(physical_addr_offset);
+    OffsetPageTable::new(level_4_page_table, physical_addr_offset)
+}
+
+/// Where the bootloader mapped all of physical memory. Set by `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _flags) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt: VirtAddr = physical_memory_offset + phys.as_u64();
+
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // Unsafe
+}
+
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let mapping_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+
+    mapping_result.expect("map_to failed").flush();
+}
+
+pub struct EmptyFrameAllocator;
+unsafe impl<S> FrameAllocator<S> for EmptyFrameAllocator
+where
+    S: PageSize,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        None
+    }
+}
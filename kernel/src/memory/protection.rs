@@ -0,0 +1,171 @@
+// src/memory/protection.rs
+
+//! Keeping kernel memory either writable or executable, never both.
+
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::TranslateResult, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use super::{physical_memory_offset, MappedRange, MappingSize, PageTableWalker};
+
+extern "C" {
+    /// The kernel's ELF header, provided by the linker. It and the program headers after it
+    /// are loaded along with the first segment.
+    static __ehdr_start: ElfHeader;
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_headers: u64,
+    section_headers: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_names: u16,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virt: u64,
+    phys: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// Turn on the NX bit and make the kernel honour read-only pages.
+///
+/// Must run before any mapping uses `NO_EXECUTE`, the bit is reserved until EFER.NXE is set.
+pub fn enable() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Address range of the kernel's code: the executable segment containing `enable`, from the
+/// kernel's own program headers.
+pub fn kernel_text() -> (VirtAddr, VirtAddr) {
+    let code = enable as usize as u64;
+    let (header, program_headers) = unsafe {
+        let header = &__ehdr_start;
+        let first = (header as *const ElfHeader as *const u8).add(header.program_headers as usize);
+        (header, first)
+    };
+    for i in 0..usize::from(header.program_header_count) {
+        let segment = unsafe {
+            (program_headers.add(i * usize::from(header.program_header_size))
+                as *const ProgramHeader)
+                .read_unaligned()
+        };
+        let end = segment.virt + segment.memory_size;
+        let executable = segment.kind == PT_LOAD && segment.flags & PF_X != 0;
+        if executable && (segment.virt..end).contains(&code) {
+            return (
+                VirtAddr::new(segment.virt).align_down(Size4KiB::SIZE),
+                VirtAddr::new(end).align_up(Size4KiB::SIZE),
+            );
+        }
+    }
+    panic!("no executable segment holds the kernel's code");
+}
+
+fn is_writable_and_executable(range: &MappedRange) -> bool {
+    range.flags.contains(PageTableFlags::WRITABLE)
+        && !range.flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Mapped ranges that are both writable and executable.
+pub fn w_xor_x_violations<'a>(
+    walker: &'a PageTableWalker<'a>,
+) -> impl Iterator<Item = MappedRange> + 'a {
+    walker.ranges().filter(is_writable_and_executable)
+}
+
+/// Fix every writable and executable mapping in `mapper`'s tables, which must be the active
+/// ones. Kernel text is made read-only, everything else non-executable.
+///
+/// Returns the number of ranges changed.
+pub fn enforce_w_xor_x(mapper: &mut OffsetPageTable<'static>) -> usize {
+    let text = kernel_text();
+    let mut fixed = 0;
+    let mut cursor = VirtAddr::new(0);
+    loop {
+        // The walker can't be held across changes to the tables.
+        let range = {
+            let walker = unsafe { PageTableWalker::active(physical_memory_offset()) };
+            let range = walker
+                .ranges()
+                .filter(|r| r.start >= cursor)
+                .find(is_writable_and_executable);
+            match range {
+                Some(range) => range,
+                None => return fixed,
+            }
+        };
+
+        let mut addr = range.start;
+        while addr < range.end() {
+            let in_text = text.0 <= addr && addr < text.1;
+            let flags = match mapper.translate(addr) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => unreachable!("walker reported {:?} as mapped", addr),
+            };
+            let flags = if in_text {
+                flags - PageTableFlags::WRITABLE
+            } else {
+                flags | PageTableFlags::NO_EXECUTE
+            };
+            update_flags(mapper, addr, range.page_size, flags);
+            addr += range.page_size.bytes();
+        }
+        fixed += 1;
+        cursor = range.end();
+    }
+}
+
+fn update_flags(
+    mapper: &mut OffsetPageTable<'static>,
+    addr: VirtAddr,
+    size: MappingSize,
+    flags: PageTableFlags,
+) {
+    let result = unsafe {
+        match size {
+            MappingSize::Size4KiB => mapper
+                .update_flags(Page::<Size4KiB>::containing_address(addr), flags)
+                .map(|flush| flush.flush()),
+            MappingSize::Size2MiB => mapper
+                .update_flags(Page::<Size2MiB>::containing_address(addr), flags)
+                .map(|flush| flush.flush()),
+            MappingSize::Size1GiB => mapper
+                .update_flags(Page::<Size1GiB>::containing_address(addr), flags)
+                .map(|flush| flush.flush()),
+        }
+    };
+    result.unwrap_or_else(|err| panic!("failed to update flags at {:?}: {:?}", addr, err));
+}
@@ -0,0 +1,333 @@
+// src/memory/walk.rs
+
+//! Read-only walks over a set of page tables.
+//!
+//! `PageTableWalker::mappings` yields every leaf entry, `ranges` merges runs of them that are
+//! contiguous in both virtual and physical memory with the same flags. The flags reported are
+//! the effective ones: a page is only writable or user accessible if every level allows it,
+//! and it's not executable if any level says so.
+
+use crate::prelude::*;
+
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// Flags that say something about how a page was used rather than how it's mapped.
+const USAGE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::ACCESSED.bits() | PageTableFlags::DIRTY.bits(),
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize {
+    /// Size of pages mapped at `depth`, where the level 4 table is depth 0.
+    fn at_depth(depth: usize) -> MappingSize {
+        match depth {
+            1 => MappingSize::Size1GiB,
+            2 => MappingSize::Size2MiB,
+            3 => MappingSize::Size4KiB,
+            _ => unreachable!("no pages are mapped by level 4 entries"),
+        }
+    }
+
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 0x1000,
+            MappingSize::Size2MiB => 0x20_0000,
+            MappingSize::Size1GiB => 0x4000_0000,
+        }
+    }
+}
+
+impl fmt::Display for MappingSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            MappingSize::Size4KiB => "4K",
+            MappingSize::Size2MiB => "2M",
+            MappingSize::Size1GiB => "1G",
+        })
+    }
+}
+
+/// A single mapped page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: MappingSize,
+    /// Effective flags, see the module docs.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.virt <= addr && addr - self.virt < self.size.bytes()
+    }
+}
+
+/// Pages that are contiguous in virtual and physical memory, with the same size and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub len: u64,
+    pub page_size: MappingSize,
+    /// Effective flags without `ACCESSED` and `DIRTY`.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn from_mapping(mapping: Mapping) -> MappedRange {
+        MappedRange {
+            start: mapping.virt,
+            phys: mapping.phys,
+            len: mapping.size.bytes(),
+            page_size: mapping.size,
+            flags: mapping.flags - USAGE_FLAGS,
+        }
+    }
+
+    /// Grow to include `mapping` if it directly follows this range.
+    fn extend(&mut self, mapping: &Mapping) -> bool {
+        let follows = self.end() == mapping.virt
+            && self.phys + self.len == mapping.phys
+            && self.page_size == mapping.size
+            && self.flags == mapping.flags - USAGE_FLAGS;
+        if follows {
+            self.len += mapping.size.bytes();
+        }
+        follows
+    }
+}
+
+/// One line: virtual range, physical start, page size and `rwxug` style permissions.
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>2} r{}{}{}{} {:>8} KiB",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.phys.as_u64(),
+            self.page_size,
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableFlags::GLOBAL, 'g'),
+            self.len / 1024
+        )
+    }
+}
+
+/// Walks page tables reachable through the physical memory mapping.
+pub struct PageTableWalker<'a> {
+    level_4_table: &'a PageTable,
+    physical_memory_offset: VirtAddr,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// # Safety
+    /// All of physical memory must be mapped at `physical_memory_offset`, and the tables must
+    /// not be changed while the walker is alive.
+    pub unsafe fn new(
+        level_4_table: &'a PageTable,
+        physical_memory_offset: VirtAddr,
+    ) -> PageTableWalker<'a> {
+        PageTableWalker {
+            level_4_table,
+            physical_memory_offset,
+        }
+    }
+
+    /// Walk the tables currently loaded in CR3.
+    ///
+    /// # Safety
+    /// As for `new`.
+    pub unsafe fn active(physical_memory_offset: VirtAddr) -> PageTableWalker<'a> {
+        let (frame, _) = Cr3::read();
+        let table = &*(physical_memory_offset + frame.start_address().as_u64()).as_ptr();
+        PageTableWalker::new(table, physical_memory_offset)
+    }
+
+    fn table(&self, phys: PhysAddr) -> &'a PageTable {
+        unsafe { &*(self.physical_memory_offset + phys.as_u64()).as_ptr() }
+    }
+
+    /// Every mapped page, in address order.
+    pub fn mappings(&self) -> Mappings<'a> {
+        Mappings {
+            physical_memory_offset: self.physical_memory_offset,
+            tables: [self.level_4_table; 4],
+            indices: [0; 4],
+            inherited: [PageTableFlags::empty(); 4],
+            depth: 0,
+        }
+    }
+
+    /// Mapped memory merged into contiguous ranges, in address order.
+    pub fn ranges(&self) -> MappedRanges<'a> {
+        MappedRanges {
+            mappings: self.mappings(),
+            pending: None,
+        }
+    }
+
+    /// The page `addr` is in, if it's mapped.
+    pub fn query(&self, addr: VirtAddr) -> Option<Mapping> {
+        let indices = [
+            u16::from(addr.p4_index()),
+            u16::from(addr.p3_index()),
+            u16::from(addr.p2_index()),
+            u16::from(addr.p1_index()),
+        ];
+        let mut table = self.level_4_table;
+        let mut flags = PageTableFlags::empty();
+        for (depth, &index) in indices.iter().enumerate() {
+            let entry = &table[index as usize];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            flags = effective_flags(depth, flags, entry.flags());
+            if depth == 3 || (depth > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+                let size = MappingSize::at_depth(depth);
+                let mapping = Mapping {
+                    virt: addr.align_down(size.bytes()),
+                    phys: entry.addr(),
+                    size,
+                    flags,
+                };
+                debug_assert!(mapping.contains(addr));
+                return Some(mapping);
+            }
+            table = self.table(entry.addr());
+        }
+        unreachable!()
+    }
+
+    /// Physical address `addr` is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.query(addr).map(|m| m.phys + (addr - m.virt))
+    }
+
+    /// Print the memory map, one range per line.
+    pub fn print_ranges(&self) {
+        for range in self.ranges() {
+            println!("{}", range);
+        }
+    }
+}
+
+fn effective_flags(depth: usize, parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    if depth == 0 {
+        return entry;
+    }
+    let restrictive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry - restrictive)
+        | (entry & parent & restrictive)
+        | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+/// Iterator over mapped pages, see `PageTableWalker::mappings`.
+pub struct Mappings<'a> {
+    physical_memory_offset: VirtAddr,
+    tables: [&'a PageTable; 4],
+    indices: [usize; 4],
+    /// Effective flags of the entries leading to `tables[depth]`.
+    inherited: [PageTableFlags; 4],
+    depth: usize,
+}
+
+impl<'a> Mappings<'a> {
+    fn current_addr(&self) -> VirtAddr {
+        let addr = self.indices[..=self.depth]
+            .iter()
+            .enumerate()
+            .fold(0, |addr, (depth, &index)| {
+                addr | ((index as u64) << (39 - 9 * depth))
+            });
+        VirtAddr::new_truncate(addr)
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let depth = self.depth;
+            if self.indices[depth] == 512 {
+                if depth == 0 {
+                    return None;
+                }
+                self.depth -= 1;
+                self.indices[self.depth] += 1;
+                continue;
+            }
+
+            let entry = &self.tables[depth][self.indices[depth]];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                self.indices[depth] += 1;
+                continue;
+            }
+            let flags = effective_flags(depth, self.inherited[depth], entry.flags());
+            if depth == 3 || (depth > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+                let mapping = Mapping {
+                    virt: self.current_addr(),
+                    phys: entry.addr(),
+                    size: MappingSize::at_depth(depth),
+                    flags,
+                };
+                self.indices[depth] += 1;
+                return Some(mapping);
+            }
+
+            let table = self.physical_memory_offset + entry.addr().as_u64();
+            self.tables[depth + 1] = unsafe { &*table.as_ptr() };
+            self.inherited[depth + 1] = flags;
+            self.indices[depth + 1] = 0;
+            self.depth += 1;
+        }
+    }
+}
+
+/// Iterator over merged ranges, see `PageTableWalker::ranges`.
+pub struct MappedRanges<'a> {
+    mappings: Mappings<'a>,
+    pending: Option<MappedRange>,
+}
+
+impl<'a> Iterator for MappedRanges<'a> {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        for mapping in &mut self.mappings {
+            if let Some(range) = self.pending.as_mut() {
+                if range.extend(&mapping) {
+                    continue;
+                }
+            }
+            if let Some(done) = self.pending.replace(MappedRange::from_mapping(mapping)) {
+                return Some(done);
+            }
+        }
+        self.pending.take()
+    }
+}
@@ -0,0 +1,234 @@
+// src/memory_manager/dma.rs
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{memory_manager, virtual_regions};
+
+/// Where in physical memory a DMA buffer has to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// Alignment of the buffer's physical address, a power of two of at least 4KiB.
+    pub align: u64,
+    /// The whole buffer must be below this address.
+    pub limit: PhysAddr,
+    /// The buffer may not cross a multiple of this many bytes.
+    pub boundary: Option<u64>,
+}
+
+impl DmaConstraints {
+    /// Page aligned, anywhere in memory.
+    pub const fn new() -> DmaConstraints {
+        DmaConstraints {
+            align: Size4KiB::SIZE,
+            limit: PhysAddr::new_truncate(u64::MAX),
+            boundary: None,
+        }
+    }
+
+    /// ISA DMA: below 16MiB, never crossing 64KiB.
+    pub const fn isa() -> DmaConstraints {
+        DmaConstraints {
+            align: Size4KiB::SIZE,
+            limit: PhysAddr::new_truncate(0x100_0000),
+            boundary: Some(0x1_0000),
+        }
+    }
+
+    /// 32 bit bus masters such as IDE, whose PRD entries can't cross 64KiB either.
+    pub const fn bus_master_32() -> DmaConstraints {
+        DmaConstraints {
+            align: Size4KiB::SIZE,
+            limit: PhysAddr::new_truncate(0x1_0000_0000),
+            boundary: Some(0x1_0000),
+        }
+    }
+
+    pub const fn align(mut self, align: u64) -> DmaConstraints {
+        self.align = align;
+        self
+    }
+
+    pub const fn below(mut self, limit: PhysAddr) -> DmaConstraints {
+        self.limit = limit;
+        self
+    }
+
+    pub const fn boundary(mut self, boundary: u64) -> DmaConstraints {
+        self.boundary = Some(boundary);
+        self
+    }
+}
+
+impl Default for DmaConstraints {
+    fn default() -> DmaConstraints {
+        DmaConstraints::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// No free physical memory satisfies the constraints.
+    OutOfMemory,
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::OutOfMemory => write!(f, "no physical memory satisfies the constraints"),
+            DmaError::OutOfVirtualSpace => write!(f, "out of kernel virtual address space"),
+            DmaError::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+impl crate::error::Error for DmaError {}
+
+/// Physically contiguous memory for devices to read and write. Freed when dropped.
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn frames(&self) -> u64 {
+        frames_for(self.len).max(1)
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+}
+
+impl fmt::Debug for DmaBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("virt", &self.virt)
+            .field("phys", &self.phys)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let frames = self.frames();
+        {
+            let mut memory_manager = memory_manager().lock();
+            memory_manager.unmap_range(self.virt, frames);
+            unsafe {
+                memory_manager.frame_allocator.deallocate_contiguous(
+                    PhysFrame::containing_address(self.phys),
+                    frames as usize,
+                );
+            }
+        }
+        virtual_regions()
+            .lock()
+            .free(self.virt, frames * Size4KiB::SIZE);
+    }
+}
+
+fn frames_for(len: usize) -> u64 {
+    ((len as u64) + Size4KiB::SIZE - 1) / Size4KiB::SIZE
+}
+
+/// Allocate a zeroed buffer of `len` bytes that satisfies `constraints`.
+pub fn allocate_dma(len: usize, constraints: DmaConstraints) -> Result<DmaBuffer, DmaError> {
+    assert!(
+        constraints.align.is_power_of_two() && constraints.align >= Size4KiB::SIZE,
+        "DMA alignment must be a power of two of at least a page"
+    );
+    let frames = frames_for(len).max(1);
+    let size = frames * Size4KiB::SIZE;
+    let boundary = constraints.boundary.map(|boundary| {
+        assert!(
+            boundary.is_power_of_two() && boundary >= Size4KiB::SIZE,
+            "DMA boundary must be a power of two of at least a page"
+        );
+        (boundary / Size4KiB::SIZE) as usize
+    });
+
+    let virt = virtual_regions()
+        .lock()
+        .allocate(size, Size4KiB::SIZE)
+        .ok_or(DmaError::OutOfVirtualSpace)?;
+
+    let result = {
+        let mut memory_manager = memory_manager().lock();
+        memory_manager
+            .frame_allocator
+            .allocate_contiguous_within(
+                frames as usize,
+                (constraints.align / Size4KiB::SIZE) as usize,
+                constraints.limit,
+                boundary,
+            )
+            .ok_or(DmaError::OutOfMemory)
+            .and_then(|start| {
+                let phys = start.start_address();
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE;
+                match memory_manager.map_range(virt, phys, frames, flags) {
+                    Ok(()) => Ok(phys),
+                    Err(err) => {
+                        unsafe {
+                            memory_manager
+                                .frame_allocator
+                                .deallocate_contiguous(start, frames as usize)
+                        };
+                        Err(DmaError::Map(err))
+                    }
+                }
+            })
+    };
+
+    match result {
+        Ok(phys) => {
+            unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size as usize) };
+            Ok(DmaBuffer { virt, phys, len })
+        }
+        Err(err) => {
+            virtual_regions().lock().free(virt, size);
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// src/memory_manager/fault.rs
+
+//! Regions of kernel address space that the page fault handler knows how to deal with.
+//!
+//! Lazy regions are reserved up front but only get a (zeroed) frame when a page is first
+//! touched. Guard regions are left unmapped on purpose and name their owner, so a fault in
+//! one can be reported as a stack overflow rather than a stray pointer.
+
+use alloc::{string::String, vec::Vec};
+use core::ptr;
+use spin::Mutex;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::{try_memory_manager, virtual_regions};
+
+static REGIONS: Mutex<Vec<FaultRegion>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+enum RegionKind {
+    Lazy { flags: PageTableFlags },
+    Guard { owner: String },
+}
+
+#[derive(Debug)]
+struct FaultRegion {
+    start: VirtAddr,
+    end: VirtAddr,
+    kind: RegionKind,
+}
+
+impl FaultRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// What `resolve_page_fault` made of a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// A frame was mapped, the faulting instruction can be retried.
+    Resolved,
+    /// The address is in a guard region, see `guard_owner`.
+    GuardPage,
+    /// Not in any registered region, or it couldn't be fixed up.
+    NotHandled,
+}
+
+/// Back `start..start + size` with memory on first access, mapped with `flags`.
+pub fn register_lazy_region(start: VirtAddr, size: u64, flags: PageTableFlags) {
+    register(FaultRegion {
+        start,
+        end: start + size,
+        kind: RegionKind::Lazy {
+            flags: flags | PageTableFlags::PRESENT,
+        },
+    });
+}
+
+/// Mark `start..start + size` as a guard owned by `owner`. It must stay unmapped.
+pub fn register_guard(start: VirtAddr, size: u64, owner: impl Into<String>) {
+    register(FaultRegion {
+        start,
+        end: start + size,
+        kind: RegionKind::Guard {
+            owner: owner.into(),
+        },
+    });
+}
+
+fn register(region: FaultRegion) {
+    let mut regions = REGIONS.lock();
+    assert!(
+        !regions
+            .iter()
+            .any(|r| r.start < region.end && region.start < r.end),
+        "fault region {:?} overlaps an existing region",
+        region
+    );
+    regions.push(region);
+}
+
+/// Forget the region starting at `start`. Pages already mapped in it are left alone.
+pub fn unregister(start: VirtAddr) {
+    REGIONS.lock().retain(|r| r.start != start);
+}
+
+/// Reserve `size` bytes of kernel address space that is backed on first touch.
+pub fn allocate_lazy(size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    let start = virtual_regions().lock().allocate(size, Size4KiB::SIZE)?;
+    register_lazy_region(start, size, flags);
+    Some(start)
+}
+
+/// Release a region from `allocate_lazy`, freeing whatever pages were touched.
+pub fn free_lazy(start: VirtAddr, size: u64) {
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    unregister(start);
+    {
+        let mut memory_manager = super::memory_manager().lock();
+        let first = Page::<Size4KiB>::containing_address(start);
+        for page in Page::range(first, first + size / Size4KiB::SIZE) {
+            memory_manager.unmap_and_free(page);
+        }
+    }
+    virtual_regions().lock().free(start, size);
+}
+
+/// Try to make the access at `addr` succeed.
+///
+/// Called from the page fault handler, so this never blocks: if the region list or the
+/// memory manager is locked by the interrupted code the fault is reported as not handled.
+pub fn resolve_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> FaultResolution {
+    let regions = match REGIONS.try_lock() {
+        Some(regions) => regions,
+        None => return FaultResolution::NotHandled,
+    };
+    let region = match regions.iter().find(|r| r.contains(addr)) {
+        Some(region) => region,
+        None => return FaultResolution::NotHandled,
+    };
+
+    let flags = match region.kind {
+        RegionKind::Guard { .. } => return FaultResolution::GuardPage,
+        RegionKind::Lazy { flags } => flags,
+    };
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // The page is there, the access just isn't allowed.
+        return FaultResolution::NotHandled;
+    }
+
+    let mut memory_manager = match try_memory_manager().and_then(|mm| mm.try_lock()) {
+        Some(memory_manager) => memory_manager,
+        None => return FaultResolution::NotHandled,
+    };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    if memory_manager
+        .map_fresh(
+            Page::range_inclusive(page, page),
+            flags | PageTableFlags::WRITABLE,
+        )
+        .is_err()
+    {
+        return FaultResolution::NotHandled;
+    }
+
+    unsafe {
+        ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+        if !flags.contains(PageTableFlags::WRITABLE) {
+            match memory_manager.mapper.update_flags(page, flags) {
+                Ok(flush) => flush.flush(),
+                Err(_) => return FaultResolution::NotHandled,
+            }
+        }
+    }
+    FaultResolution::Resolved
+}
+
+/// Call `f` with the owner of the guard region containing `addr`, if there is one.
+///
+/// Like `resolve_page_fault` this is safe to call from an exception handler.
+pub fn guard_owner<R>(addr: VirtAddr, f: impl FnOnce(Option<&str>) -> R) -> R {
+    let regions = match REGIONS.try_lock() {
+        Some(regions) => regions,
+        None => return f(None),
+    };
+    let owner = regions.iter().find_map(|r| match r.kind {
+        RegionKind::Guard { ref owner } if r.contains(addr) => Some(owner.as_str()),
+        _ => None,
+    });
+    f(owner)
+}
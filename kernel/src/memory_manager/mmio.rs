@@ -0,0 +1,155 @@
+// src/memory_manager/mmio.rs
+
+use core::{fmt, mem};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{memory_manager, virtual_regions};
+
+/// Caching used for a device mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    /// Strong uncacheable, for device registers.
+    Uncached,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for MmioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MmioError::OutOfVirtualSpace => write!(f, "out of kernel virtual address space"),
+            MmioError::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+impl crate::error::Error for MmioError {}
+
+/// Physical memory mapped into kernel space. Unmapped when dropped.
+pub struct MmioMapping {
+    /// Start of the first mapped page.
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioMapping {
+    /// Mapped address of the physical address the mapping was created with.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base + page_offset(self.phys)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    /// Give up ownership without unmapping. Returns the mapped address of `phys_addr()`.
+    pub fn into_raw(self) -> VirtAddr {
+        let virt = self.virt_addr();
+        mem::forget(self);
+        virt
+    }
+
+    /// Take back ownership of a mapping released with `into_raw`.
+    ///
+    /// # Safety
+    /// `virt`, `phys` and `len` must be the values of a mapping given up with `into_raw`.
+    pub unsafe fn from_raw(virt: VirtAddr, phys: PhysAddr, len: usize) -> MmioMapping {
+        MmioMapping {
+            base: virt - page_offset(phys),
+            phys,
+            len,
+        }
+    }
+
+    fn mapped_size(&self) -> u64 {
+        mapped_size(self.phys, self.len)
+    }
+}
+
+impl fmt::Debug for MmioMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmioMapping")
+            .field("virt", &self.virt_addr())
+            .field("phys", &self.phys)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        let pages = self.mapped_size() / Size4KiB::SIZE;
+        memory_manager().lock().unmap_range(self.base, pages);
+        virtual_regions().lock().free(self.base, self.mapped_size());
+    }
+}
+
+fn page_offset(phys: PhysAddr) -> u64 {
+    phys.as_u64() % Size4KiB::SIZE
+}
+
+/// Bytes of whole pages needed to map `len` bytes from `phys`.
+fn mapped_size(phys: PhysAddr, len: usize) -> u64 {
+    let start = phys.align_down(Size4KiB::SIZE);
+    let end = (phys + len as u64).align_up(Size4KiB::SIZE);
+    (end - start).max(Size4KiB::SIZE)
+}
+
+/// Map `len` bytes of physical memory starting at `phys` into kernel space.
+///
+/// The memory isn't treated as RAM, so the frames are never handed to the frame allocator.
+/// The mapping is writable and not executable.
+pub fn map_mmio(phys: PhysAddr, len: usize, cache_mode: CacheMode) -> Result<MmioMapping, MmioError> {
+    let size = mapped_size(phys, len);
+    let base = virtual_regions()
+        .lock()
+        .allocate(size, Size4KiB::SIZE)
+        .ok_or(MmioError::OutOfVirtualSpace)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_mode.flags();
+    let result = memory_manager().lock().map_range(
+        base,
+        phys.align_down(Size4KiB::SIZE),
+        size / Size4KiB::SIZE,
+        flags,
+    );
+
+    match result {
+        Ok(()) => Ok(MmioMapping { base, phys, len }),
+        Err(err) => {
+            virtual_regions().lock().free(base, size);
+            Err(MmioError::Map(err))
+        }
+    }
+}
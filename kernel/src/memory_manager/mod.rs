@@ -0,0 +1,180 @@
+// src/memory_manager/mod.rs
+
+pub mod dma;
+pub mod fault;
+pub mod mmio;
+pub mod stack;
+pub mod virtual_region;
+
+pub use dma::{allocate_dma, DmaBuffer, DmaConstraints, DmaError};
+pub use mmio::{map_mmio, CacheMode, MmioError, MmioMapping};
+pub use stack::{KernelStack, StackError};
+pub use virtual_region::VirtualRegionAllocator;
+
+use conquer_once::spin::OnceCell;
+use spin::lock_api::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    memory::{self, protection, BitmapFrameAllocator, PageTableWalker},
+    sync::IrqMutex,
+};
+
+// Kernel virtual address space layout:
+//   0x4000_0000_0000  physical memory, mapped by the bootloader
+//   0x4444_4444_0000  heap, see `allocator::HEAP_START`
+//   0x5000_0000_0000  dynamic region, handed out by `virtual_regions()`
+//   0x6000_0000_0000  kernel stacks, see `stack::KernelStack`
+
+/// Start of the region handed out by `virtual_regions()`.
+pub const DYNAMIC_REGION_START: u64 = 0x5000_0000_0000;
+pub const DYNAMIC_REGION_SIZE: u64 = 0x0100_0000_0000; // 1 TiB
+
+static MEMORY_MANAGER: OnceCell<IrqMutex<MemoryManager>> = OnceCell::uninit();
+static VIRTUAL_REGIONS: OnceCell<Mutex<VirtualRegionAllocator>> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+impl MemoryManager {
+    /// Back every page in `pages` with a freshly allocated frame.
+    ///
+    /// On failure any pages mapped so far are unmapped and their frames returned.
+    pub fn map_fresh(
+        &mut self,
+        pages: PageRangeInclusive<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        for (i, page) in pages.enumerate() {
+            let result = self
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| {
+                    let result = unsafe {
+                        self.mapper
+                            .map_to(page, frame, flags, &mut self.frame_allocator)
+                    };
+                    if result.is_err() {
+                        unsafe { self.frame_allocator.deallocate_frame(frame) };
+                    }
+                    result
+                });
+
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    for mapped in pages.take(i) {
+                        self.unmap_and_free(mapped);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `count` pages from `virt` to the frames from `phys`.
+    ///
+    /// On failure any pages mapped so far are unmapped again.
+    pub fn map_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        count: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let first_page = Page::<Size4KiB>::containing_address(virt);
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        for i in 0..count {
+            let result = unsafe {
+                self.mapper
+                    .map_to(first_page + i, first_frame + i, flags, &mut self.frame_allocator)
+            };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    self.unmap_range(virt, i);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap `count` pages from `virt` without freeing the frames behind them.
+    pub fn unmap_range(&mut self, virt: VirtAddr, count: u64) {
+        let first_page = Page::<Size4KiB>::containing_address(virt);
+        for i in 0..count {
+            if let Ok((_, flush)) = self.mapper.unmap(first_page + i) {
+                flush.flush();
+            }
+        }
+    }
+
+    /// Unmap `page` and return its frame to the frame allocator.
+    pub fn unmap_and_free(&mut self, page: Page<Size4KiB>) {
+        if let Ok((frame, flush)) = self.mapper.unmap(page) {
+            flush.flush();
+            unsafe { self.frame_allocator.deallocate_frame(frame) };
+        }
+    }
+
+    /// Walk the active page tables. Holding `&self` keeps them from changing under the walk.
+    pub fn walker(&self) -> PageTableWalker<'_> {
+        unsafe { PageTableWalker::active(memory::physical_memory_offset()) }
+    }
+}
+
+/// Install the kernel's memory manager. Must be called once, after the heap is initialized.
+///
+/// Any mapping left both writable and executable by the bootloader is fixed up here, and the
+/// exception stacks are moved to stacks with guard pages.
+pub fn init(mut memory_manager: MemoryManager) -> &'static IrqMutex<MemoryManager> {
+    protection::enforce_w_xor_x(&mut memory_manager.mapper);
+    MEMORY_MANAGER
+        .try_init_once(|| IrqMutex::new(memory_manager))
+        .expect("Memory manager already initialized");
+    VIRTUAL_REGIONS.init_once(|| {
+        Mutex::new(VirtualRegionAllocator::new(
+            VirtAddr::new(DYNAMIC_REGION_START),
+            DYNAMIC_REGION_SIZE,
+        ))
+    });
+    stack::init();
+    crate::gdt::init_ist_stacks();
+    memory_manager()
+}
+
+/// The shared memory manager.
+///
+/// Interrupts are disabled while it is locked, so the heap can always grow from an interrupt
+/// handler. Code holding the lock must not allocate from the heap, as growing it takes the
+/// same lock.
+pub fn memory_manager() -> &'static IrqMutex<MemoryManager> {
+    try_memory_manager().expect("Memory manager not initialized")
+}
+
+/// Allocator for the dynamic region of kernel address space.
+///
+/// Kept apart from the `MemoryManager` lock as it allocates from the heap, which may need
+/// the memory manager to grow.
+pub fn virtual_regions() -> &'static Mutex<VirtualRegionAllocator> {
+    VIRTUAL_REGIONS
+        .get()
+        .expect("Memory manager not initialized")
+}
+
+/// The shared memory manager, if it has been initialized.
+pub fn try_memory_manager() -> Option<&'static IrqMutex<MemoryManager>> {
+    MEMORY_MANAGER.get()
+}
@@ -0,0 +1,129 @@
+// src/memory_manager/stack.rs
+
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::lock_api::Mutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{fault, memory_manager, VirtualRegionAllocator};
+
+/// Start of the region kernel stacks are allocated from.
+pub const STACK_REGION_START: u64 = 0x6000_0000_0000;
+pub const STACK_REGION_SIZE: u64 = 0x10_0000_0000; // 64 GiB
+/// Unmapped bytes left below every stack.
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+static STACK_REGIONS: OnceCell<Mutex<VirtualRegionAllocator>> = OnceCell::uninit();
+
+pub(super) fn init() {
+    STACK_REGIONS.init_once(|| {
+        Mutex::new(VirtualRegionAllocator::new(
+            VirtAddr::new(STACK_REGION_START),
+            STACK_REGION_SIZE,
+        ))
+    });
+}
+
+fn stack_regions() -> &'static Mutex<VirtualRegionAllocator> {
+    STACK_REGIONS
+        .try_get()
+        .expect("Memory manager not initialized")
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::OutOfVirtualSpace => write!(f, "out of kernel stack address space"),
+            StackError::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+impl crate::error::Error for StackError {}
+
+/// A mapped kernel stack with an unmapped guard page below it.
+///
+/// Running off the bottom faults in the guard page, which the fault handlers report as a
+/// stack overflow in the stack's owner. Unmapped and freed when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// Start of the guard page.
+    base: VirtAddr,
+    /// Usable size, without the guard page.
+    size: u64,
+}
+
+impl KernelStack {
+    /// Map a stack of at least `size` bytes. `owner` names it in overflow reports.
+    pub fn new(size: u64, owner: impl Into<String>) -> Result<KernelStack, StackError> {
+        let size = (size.max(1) + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        let base = stack_regions()
+            .lock()
+            .allocate(GUARD_SIZE + size, Size4KiB::SIZE)
+            .ok_or(StackError::OutOfVirtualSpace)?;
+
+        let bottom = base + GUARD_SIZE;
+        let pages = Page::range_inclusive(
+            Page::<Size4KiB>::containing_address(bottom),
+            Page::containing_address(bottom + (size - 1)),
+        );
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if let Err(err) = memory_manager().lock().map_fresh(pages, flags) {
+            stack_regions().lock().free(base, GUARD_SIZE + size);
+            return Err(StackError::Map(err));
+        }
+        fault::register_guard(base, GUARD_SIZE, owner);
+
+        Ok(KernelStack { base, size })
+    }
+
+    /// Initial stack pointer, the end of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + self.size
+    }
+
+    /// Lowest usable address.
+    pub fn bottom(&self) -> VirtAddr {
+        self.base + GUARD_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether `addr` is in the stack's guard page.
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        self.base <= addr && addr < self.bottom()
+    }
+
+    /// Keep the stack mapped forever, for stacks the CPU switches to on its own.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        fault::unregister(self.base);
+        {
+            let mut memory_manager = memory_manager().lock();
+            let first = Page::<Size4KiB>::containing_address(self.bottom());
+            for page in Page::range(first, first + self.size / Size4KiB::SIZE) {
+                memory_manager.unmap_and_free(page);
+            }
+        }
+        stack_regions().lock().free(self.base, GUARD_SIZE + self.size);
+    }
+}
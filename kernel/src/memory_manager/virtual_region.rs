@@ -0,0 +1,75 @@
+// src/memory_manager/virtual_region.rs
+
+use alloc::collections::BTreeMap;
+use x86_64::VirtAddr;
+
+/// Hands out non-overlapping ranges of kernel virtual address space.
+///
+/// Only address space is tracked here, mapping the pages is up to the caller.
+#[derive(Debug)]
+pub struct VirtualRegionAllocator {
+    /// Free ranges keyed by start address, values are the range lengths.
+    free: BTreeMap<u64, u64>,
+}
+
+impl VirtualRegionAllocator {
+    pub fn new(start: VirtAddr, size: u64) -> VirtualRegionAllocator {
+        let mut free = BTreeMap::new();
+        free.insert(start.as_u64(), size);
+        VirtualRegionAllocator { free }
+    }
+
+    /// Reserve `size` bytes aligned to `align`, which must be a power of two.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let (run_start, run_size, start) = self.free.iter().find_map(|(&run_start, &run_size)| {
+            let start = (run_start + align - 1) & !(align - 1);
+            if start + size <= run_start + run_size {
+                Some((run_start, run_size, start))
+            } else {
+                None
+            }
+        })?;
+
+        self.free.remove(&run_start);
+        if start > run_start {
+            self.free.insert(run_start, start - run_start);
+        }
+        let end = start + size;
+        let run_end = run_start + run_size;
+        if run_end > end {
+            self.free.insert(end, run_end - end);
+        }
+        Some(VirtAddr::new(start))
+    }
+
+    /// Give back a range returned by `allocate`.
+    pub fn free(&mut self, start: VirtAddr, size: u64) {
+        assert!(!self.overlaps_free(start, size), "virtual region freed twice");
+        let mut start = start.as_u64();
+        let mut size = size;
+
+        if let Some((&prev_start, &prev_size)) = self.free.range(..start).next_back() {
+            if prev_start + prev_size == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
+                size += prev_size;
+            }
+        }
+        let end = start + size;
+        if let Some(&next_size) = self.free.get(&end) {
+            self.free.remove(&end);
+            size += next_size;
+        }
+        self.free.insert(start, size);
+    }
+
+    /// Whether any part of the range is free.
+    pub fn overlaps_free(&self, start: VirtAddr, size: u64) -> bool {
+        let start = start.as_u64();
+        let end = start + size;
+        let prev = self.free.range(..=start).next_back();
+        prev.map_or(false, |(&prev_start, &prev_size)| prev_start + prev_size > start)
+            || self.free.range(start..end).next().is_some()
+    }
+}
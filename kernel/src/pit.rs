@@ -1,11 +1,16 @@
+// src/pit.rs
+
+//! The 8253/8254 programmable interval timer. Channel 0 drives the timer interrupt.
+
 use bitflags::bitflags;
+use core::sync::atomic::{AtomicU16, Ordering};
 use spin::Mutex;
 use x86_64::instructions::{interrupts, port::{Port, PortWriteOnly}};
 
 struct Pit {
     channel_0: Port<u8>,
     _channel_1: Port<u8>,
-    _channel_2: Port<u8>,
+    channel_2: Port<u8>,
     command: PortWriteOnly<u8>
 }
 
@@ -13,11 +18,17 @@ static PIT: Mutex<Pit> = Mutex::new(
     Pit {
         channel_0: Port::new(0x40),
         _channel_1: Port::new(0x41),
-        _channel_2: Port::new(0x42),
+        channel_2: Port::new(0x42),
         command: PortWriteOnly::new(0x43),
     }
 );
 
+/// Frequency the PIT counters run at, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// What channel 0 counts down from. 0 stands for 65536, the power on default.
+static RELOAD_VALUE: AtomicU16 = AtomicU16::new(0);
+
 bitflags! {
     struct CommandFlags: u8 {
         // Channel select
@@ -53,29 +64,103 @@ bitflags! {
     }
 }
 
+/// Make channel 0 fire `frequency` times per second, as close as the divider allows.
+pub fn init(frequency: u32) {
+    let divider = (BASE_FREQUENCY + frequency / 2) / frequency;
+    let reload = if divider >= 0x1_0000 { 0 } else { divider.max(2) as u16 };
+    interrupts::without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            pit.command.write(
+                (CommandFlags::CHANNEL_0
+                    | CommandFlags::ACCESS_MODE_BOTH
+                    | CommandFlags::OPERATING_MODE_2
+                    | CommandFlags::BINARY_MODE)
+                    .bits(),
+            );
+        }
+        set_reload_value(&mut pit, reload);
+        RELOAD_VALUE.store(reload, Ordering::SeqCst);
+    });
+}
+
+/// Make channel 0 fire once, `count` PIT cycles from now, instead of periodically.
+pub fn start_one_shot(count: u16) {
+    interrupts::without_interrupts(|| {
+        let mut pit = PIT.lock();
+        unsafe {
+            pit.command.write(
+                (CommandFlags::CHANNEL_0
+                    | CommandFlags::ACCESS_MODE_BOTH
+                    | CommandFlags::OPERATING_MODE_0
+                    | CommandFlags::BINARY_MODE)
+                    .bits(),
+            );
+        }
+        set_reload_value(&mut pit, count);
+    });
+}
+
+/// PIT cycles between two timer interrupts.
+pub fn cycles_per_tick() -> u32 {
+    match RELOAD_VALUE.load(Ordering::Relaxed) {
+        0 => 0x1_0000,
+        reload => u32::from(reload),
+    }
+}
+
+/// Channel 0's counter. Counts down from the reload value to 0, once per tick.
 pub fn current_count() -> u16 {
-    let mut pit = PIT.lock();
     interrupts::without_interrupts(|| {
-        
-        let count = unsafe {
-            pit.command.write( CommandFlags::CHANNEL_0.bits() );
+        let mut pit = PIT.lock();
+        unsafe {
+            pit.command.write(
+                (CommandFlags::CHANNEL_0 | CommandFlags::ACCESS_LATCH_COUNT_VALUE_COMMAND).bits(),
+            );
 
             let count_lo = pit.channel_0.read();
             let count_hi = pit.channel_0.read();
             u16::from_le_bytes([count_lo, count_hi])
-        };
-
-        count 
+        }
     })
 }
 
-fn set_reload_value(value: u16) {
-    let mut pit = PIT.lock();
+/// Run `counter` before and after channel 2 counts down `count` PIT cycles and return the
+/// difference. Used to calibrate other clocks. Interrupts are off meanwhile, so the
+/// measurement isn't stretched and the PIT lock can't be taken by an interrupt handler.
+///
+/// Channel 2 is gated through port 0x61, which also controls the PC speaker. The speaker is
+/// kept off.
+pub fn measure_with_channel_2(count: u16, mut counter: impl FnMut() -> u64) -> u64 {
     interrupts::without_interrupts(|| {
-        let [lo, hi] = value.to_le_bytes();
+        let mut gate: Port<u8> = Port::new(0x61);
+        let mut pit = PIT.lock();
         unsafe {
-            pit.channel_0.write(lo);
-            pit.channel_0.write(hi);
+            let value = gate.read();
+            gate.write((value & !0x02) | 0x01);
+            pit.command.write(
+                (CommandFlags::CHANNEL_2
+                    | CommandFlags::ACCESS_MODE_BOTH
+                    | CommandFlags::OPERATING_MODE_0
+                    | CommandFlags::BINARY_MODE)
+                    .bits(),
+            );
+            let [lo, hi] = count.to_le_bytes();
+            pit.channel_2.write(lo);
+            pit.channel_2.write(hi);
+
+            let start = counter();
+            // OUT2 goes high once the count runs out.
+            while gate.read() & 0x20 == 0 {}
+            counter().wrapping_sub(start)
         }
     })
 }
+
+fn set_reload_value(pit: &mut Pit, value: u16) {
+    let [lo, hi] = value.to_le_bytes();
+    unsafe {
+        pit.channel_0.write(lo);
+        pit.channel_0.write(hi);
+    }
+}
@@ -4,6 +4,67 @@ use alloc::sync::{Arc, Weak};
 use crossbeam::queue::SegQueue;
 use futures::{future::poll_fn, task::AtomicWaker};
 
+use x86_64::instructions::interrupts;
+
+use crate::tasks::coop;
+
+/// Spin lock that keeps interrupts disabled while it is held, for data interrupt handlers
+/// also need. A handler can then never spin on a lock held by the code it interrupted.
+pub type IrqMutex<T> = lock_api::Mutex<RawIrqMutex, T>;
+pub type IrqMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawIrqMutex, T>;
+
+pub struct RawIrqMutex {
+    locked: AtomicBool,
+    /// Whether interrupts were enabled before the lock was taken, restored on unlock.
+    interrupts_were_enabled: AtomicBool,
+}
+
+unsafe impl lock_api::RawMutex for RawIrqMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawIrqMutex = RawIrqMutex {
+        locked: AtomicBool::new(false),
+        interrupts_were_enabled: AtomicBool::new(false),
+    };
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            // Wait with interrupts in whatever state the caller had them.
+            while self.is_locked() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let locked = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if locked {
+            self.interrupts_were_enabled.store(enabled, Ordering::Relaxed);
+        } else if enabled {
+            interrupts::enable();
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        let enabled = self.interrupts_were_enabled.load(Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Default)]
 pub struct Mutex<T> {
     locked: AtomicBool,
@@ -44,6 +105,7 @@ impl<T> Mutex<T> {
     /// Async function that returns once it's obtained the lock.
     /// Will attempt at least once before queue to save on memory.
     async fn wait_and_lock(&self) {
+        coop::consume_budget().await;
         if self.inner_try_lock_().is_ok() {
             // We have the lock!
             return;
@@ -0,0 +1,72 @@
+// src/tasks/coop.rs
+
+//! Cooperative scheduling budget.
+//!
+//! A task that keeps finding its channel or lock ready never returns `Pending` by itself, and
+//! nothing else runs in the meantime. Every poll by the executor gets a budget of `BUDGET`
+//! operations, which the kernel's async primitives consume. Once it's used up they return
+//! `Pending` and wake the task again, so it goes to the back of its ready queue.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+};
+
+/// Operations a task can do in one poll.
+pub const BUDGET: u32 = 128;
+
+/// Outside the executor, e.g. in tests polling futures by hand, nothing is counted.
+const UNCONSTRAINED: u32 = u32::MAX;
+
+/// Budget left for the running task. Only one task runs at a time.
+static REMAINING: AtomicU32 = AtomicU32::new(UNCONSTRAINED);
+
+/// Run `f` with a fresh budget.
+pub(super) fn budget<R>(f: impl FnOnce() -> R) -> R {
+    let previous = REMAINING.swap(BUDGET, Ordering::Relaxed);
+    let result = f();
+    REMAINING.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Budget left in this poll, `None` if not running in the executor.
+pub fn remaining() -> Option<u32> {
+    match REMAINING.load(Ordering::Relaxed) {
+        UNCONSTRAINED => None,
+        remaining => Some(remaining),
+    }
+}
+
+/// Use up one unit of budget. `Pending` once there's none left, and the task is woken to be
+/// polled again later.
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    match REMAINING.load(Ordering::Relaxed) {
+        UNCONSTRAINED => Poll::Ready(()),
+        0 => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        remaining => {
+            REMAINING.store(remaining - 1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Use up one unit of budget, yielding if there's none left. For async functions that
+/// may not otherwise yield.
+pub fn consume_budget() -> impl Future<Output = ()> {
+    ConsumeBudget
+}
+
+struct ConsumeBudget;
+
+impl Future for ConsumeBudget {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        poll_proceed(cx)
+    }
+}
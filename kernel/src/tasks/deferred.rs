@@ -0,0 +1,115 @@
+// src/tasks/deferred.rs
+
+//! Work that interrupt handlers hand off to task context.
+//!
+//! Interrupt handlers must do nothing but acknowledge their device and `defer` whatever else
+//! needs doing. In particular they may not print or take any lock a task could be holding:
+//! the task they interrupted might hold it and never get to release it. Deferred work runs
+//! in an ordinary task with interrupts enabled, so it is free to do both.
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use conquer_once::spin::OnceCell;
+use crossbeam::queue::ArrayQueue;
+use futures::{task::AtomicWaker, Future};
+
+use super::{Priority, Task};
+
+/// Work items that can be queued before the oldest ones have to run.
+pub const QUEUE_SIZE: usize = 256;
+
+/// Function run in task context with the `data` it was deferred with.
+pub type Work = fn(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// `init` wasn't called yet.
+    Uninitialized,
+    /// The queue is full. The work item was dropped.
+    Full,
+}
+
+static QUEUE: OnceCell<ArrayQueue<(Work, usize)>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Set up the queue. The returned task runs deferred work and has to be spawned.
+pub fn init() -> Task {
+    QUEUE
+        .try_init_once(|| ArrayQueue::new(QUEUE_SIZE))
+        .expect("Deferred work queue already initialized");
+    Task::new(run_deferred_work(), "deferred work").with_priority(Priority::Deferred)
+}
+
+/// Queue `work` to be called with `data` from task context. Safe to call from interrupt
+/// handlers: it doesn't lock or allocate.
+pub fn defer(work: Work, data: usize) -> Result<(), DeferError> {
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Err(DeferError::Uninitialized);
+        }
+    };
+    match queue.push((work, data)) {
+        Ok(()) => {
+            WAKER.wake();
+            Ok(())
+        }
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            Err(DeferError::Full)
+        }
+    }
+}
+
+/// Work items that couldn't be queued so far.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run everything queued right now, in order. Returns how many items ran.
+pub fn run_pending() -> usize {
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    while let Some((work, data)) = queue.pop() {
+        work(data);
+        count += 1;
+    }
+    count
+}
+
+async fn run_deferred_work() {
+    loop {
+        WorkQueued.await;
+        run_pending();
+    }
+}
+
+/// Ready once there is deferred work to run.
+struct WorkQueued;
+
+impl Future for WorkQueued {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let queue = QUEUE.try_get().expect("Deferred work queue not initialized");
+        if !queue.is_empty() {
+            return Poll::Ready(());
+        }
+        WAKER.register(cx.waker());
+        // An interrupt might have queued something before we registered.
+        if queue.is_empty() {
+            Poll::Pending
+        } else {
+            WAKER.take();
+            Poll::Ready(())
+        }
+    }
+}
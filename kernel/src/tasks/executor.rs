@@ -1,49 +1,287 @@
-use super::{mpsc::Sender, Task, TaskId};
+// src/tasks/executor.rs
+
+//! Single threaded executor.
+//!
+//! Spawned tasks go through an unbounded queue. Waking a task never allocates, so it's safe
+//! from interrupt handlers: every task has a `woken` flag, and woken task ids are pushed into
+//! a fixed size ready queue. If that queue is full the executor is told to look through the
+//! flags instead, so no wakeup is lost either way.
+//!
+//! Each `Priority` has its own ready queue, and higher classes are drained first. So that
+//! background tasks can't starve, one of them is polled after
+//! `BACKGROUND_STARVATION_LIMIT` polls of other tasks while they wait.
+//!
+//! `task_list` reports what every task is up to. Spawning, polling and completing
+//! tasks is only printed with `set_tracing(true)`.
+//!
+//! Every poll runs with a `coop` budget, and polls taking longer than the watchdog threshold
+//! are counted and reported, since a task stuck in a synchronous loop holds up all others.
+//! Reports are printed through `deferred` work, at most once per `WATCHDOG_REPORT_INTERVAL`
+//! for each task, so the executor loop never waits on the console.
+
+use super::join::{self, JoinHandle};
+use super::{coop, deferred, Priority, Task, TaskId};
 use crate::prelude::*;
+use crate::time::{Duration, Instant};
 use alloc::{collections::BTreeMap, sync::Arc};
 use alloc::{prelude::v1::*, task::Wake};
 use conquer_once::spin::OnceCell;
-use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
-use crossbeam::queue::ArrayQueue;
+use core::{convert::TryFrom, fmt};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam::queue::{ArrayQueue, SegQueue};
 use futures::Future;
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::instructions::interrupts;
 
+/// Woken tasks of one priority that fit in the ready queue before the executor has to scan
+/// for them.
+pub const READY_QUEUE_SIZE: usize = 1024;
+/// Polls of higher priority tasks after which a waiting background task runs.
+pub const BACKGROUND_STARVATION_LIMIT: usize = 32;
+
 pub struct Executor {
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    ready: Arc<ReadyQueue>,
     tasks: BTreeMap<TaskId, Task>,
-    waker_cache: BTreeMap<TaskId, Waker>,
-    new_tasks: Arc<ArrayQueue<Task>>,
+    wakers: BTreeMap<TaskId, TaskSlot>,
+    new_tasks: Arc<SegQueue<Task>>,
+    /// Polls since a background task last ran while one was ready.
+    background_starved: usize,
+}
+
+/// Executor side state of a task.
+struct TaskSlot {
+    record: Arc<TaskRecord>,
+    waker: Waker,
+}
+
+/// What `task_list` knows about a task. Shared between the executor and `REGISTRY`.
+struct TaskRecord {
+    id: TaskId,
+    desc: Option<String>,
+    priority: Priority,
+    task_waker: Arc<TaskWaker>,
+    stats: Mutex<PollStats>,
+}
+
+#[derive(Default)]
+struct PollStats {
+    polls: u64,
+    busy: Duration,
+    longest_poll: Duration,
+    slow_polls: u64,
+    /// When the last slow poll report was queued.
+    last_report: Option<Instant>,
+}
+
+impl TaskRecord {
+    fn info(&self, now: Duration) -> TaskInfo {
+        let woken_at = Duration::from_nanos(self.task_waker.woken_at.load(Ordering::Relaxed));
+        let state = if self.task_waker.woken.load(Ordering::Acquire) {
+            TaskState::Ready
+        } else {
+            TaskState::Pending
+        };
+        let stats = self.stats.lock();
+        TaskInfo {
+            id: self.id,
+            desc: self.desc.clone(),
+            priority: self.priority,
+            state,
+            polls: stats.polls,
+            busy: stats.busy,
+            since_wake: now.checked_sub(woken_at).unwrap_or_default(),
+            longest_poll: stats.longest_poll,
+            slow_polls: stats.slow_polls,
+        }
+    }
+}
+
+lazy_static! {
+    /// Live tasks of every executor, for `task_list`.
+    static ref REGISTRY: Mutex<BTreeMap<TaskId, Arc<TaskRecord>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Every live task, by id. Can be called from tasks. Tasks spawned since their executor
+/// last ran aren't included.
+pub fn task_list() -> Vec<TaskInfo> {
+    let now = Instant::now().as_duration();
+    REGISTRY.lock().values().map(|record| record.info(now)).collect()
+}
+
+/// Default for `set_watchdog_threshold`.
+pub const DEFAULT_WATCHDOG_THRESHOLD: Duration = Duration::from_millis(10);
+
+static WATCHDOG_THRESHOLD_NANOS: AtomicU64 =
+    AtomicU64::new(DEFAULT_WATCHDOG_THRESHOLD.as_nanos() as u64);
+
+/// Report tasks that spend longer than `threshold` in a single poll.
+pub fn set_watchdog_threshold(threshold: Duration) {
+    let nanos = u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX);
+    WATCHDOG_THRESHOLD_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+pub fn watchdog_threshold() -> Duration {
+    Duration::from_nanos(WATCHDOG_THRESHOLD_NANOS.load(Ordering::Relaxed))
+}
+
+/// Shortest time between two slow poll reports for the same task.
+pub const WATCHDOG_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Deferred work printing a slow poll report for the task with id `task_id`.
+fn report_slow_polls(task_id: usize) {
+    let record = REGISTRY.lock().get(&TaskId(task_id as u64)).cloned();
+    // The task may have finished since.
+    if let Some(record) = record {
+        let (slow_polls, longest_poll) = {
+            let stats = record.stats.lock();
+            (stats.slow_polls, stats.longest_poll)
+        };
+        println!(
+            "watchdog: task {} ({}) had {} slow polls, the longest took {:?}",
+            record.id,
+            record.desc.as_deref().unwrap_or("-"),
+            slow_polls,
+            longest_poll
+        );
+    }
+}
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Print every task spawned, polled and completed.
+pub fn set_tracing(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_tracing() -> bool {
+    TRACE.load(Ordering::Relaxed)
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if is_tracing() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Whether a task is waiting to be polled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Pending,
+}
+
+/// A live task, as seen by `Executor::task_list`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub desc: Option<String>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    /// Total time spent in `poll`.
+    pub busy: Duration,
+    /// Time since the task was last woken, or spawned.
+    pub since_wake: Duration,
+    pub longest_poll: Duration,
+    /// Polls that took longer than the watchdog threshold.
+    pub slow_polls: u64,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} {:?} polls={} busy={:?} since_wake={:?} longest={:?} slow={} {}",
+            self.id,
+            self.priority,
+            self.state,
+            self.polls,
+            self.busy,
+            self.since_wake,
+            self.longest_poll,
+            self.slow_polls,
+            self.desc.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+/// Tasks waiting to be polled.
+struct ReadyQueue {
+    /// Indexed by `Priority`.
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+    /// A wakeup didn't fit into its queue.
+    overflowed: AtomicBool,
 }
 
-static NEW_TASK_QUEUE: OnceCell<Arc<ArrayQueue<Task>>> = OnceCell::uninit();
+impl ReadyQueue {
+    fn queue(&self, priority: Priority) -> &ArrayQueue<TaskId> {
+        &self.queues[priority as usize]
+    }
+
+    fn push(&self, priority: Priority, task_id: TaskId) {
+        if self.queue(priority).push(task_id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
 
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+            && !self.overflowed.load(Ordering::Acquire)
+    }
+}
+
+static NEW_TASK_QUEUE: OnceCell<Arc<SegQueue<Task>>> = OnceCell::uninit();
+
+/// Hand `ts` to the executor. Allocates, so not for interrupt handlers.
 pub fn spawn_task(ts: Task) {
     NEW_TASK_QUEUE
         .get()
         .expect("Task queue not inialized")
-        .push(ts)
-        .expect("Task queue full");
+        .push(ts);
 }
 
-pub fn spawn(fut: impl Future<Output = ()> + Send + 'static, desc: impl ToString) {
-    spawn_task(Task::new(fut, desc));
+/// Run `fut` on the executor. The returned handle resolves to its output.
+pub fn spawn<F>(fut: F, desc: impl ToString) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (future, handle) = join::wrap(fut);
+    spawn_task(Task::new(future, desc));
+    handle
 }
 
 impl Executor {
     pub fn new() -> Box<Executor> {
-        let queue = NEW_TASK_QUEUE.get_or_init(|| Arc::new(ArrayQueue::new(128)));
+        let queue = NEW_TASK_QUEUE.get_or_init(|| Arc::new(SegQueue::new()));
 
         Box::new(Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(128)),
-            waker_cache: BTreeMap::new(),
+            ready: Arc::new(ReadyQueue {
+                queues: [
+                    ArrayQueue::new(READY_QUEUE_SIZE),
+                    ArrayQueue::new(READY_QUEUE_SIZE),
+                    ArrayQueue::new(READY_QUEUE_SIZE),
+                ],
+                overflowed: AtomicBool::new(false),
+            }),
+            wakers: BTreeMap::new(),
             new_tasks: queue.clone(),
+            background_starved: 0,
         })
     }
 
-    /// Attempt to spawn a task or return it back to the caller.
-    pub fn spawn_task(&self, task: Task) -> Result<(), Task> {
-        self.new_tasks.push(task)
+    /// Spawn a task. The queue is unbounded, so this can't fail.
+    pub fn spawn_task(&self, task: Task) {
+        self.new_tasks.push(task);
     }
 
     pub fn run(&mut self) -> ! {
@@ -55,91 +293,189 @@ impl Executor {
 
     pub fn sleep_if_idle(&mut self) {
         interrupts::disable();
-        if self.task_queue.is_empty() && self.new_tasks.is_empty() {
+        if self.ready.is_empty() && self.new_tasks.is_empty() {
             interrupts::enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
 
+    /// Take in new tasks and poll the tasks ready at this point, once each.
     pub fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-            new_tasks,
-        } = self;
-        
-        while let Some(task) = new_tasks.pop() {
-
+        while let Some(task) = self.new_tasks.pop() {
             let task_id = task.id;
             if let Some(ref desc) = task.desc {
-                println!("new task: {}", desc);
+                trace!("new task: {}", desc);
             } else {
-                println!("new task: {:?}", task_id)
+                trace!("new task: {:?}", task_id)
             }
-            tasks.insert(task_id, task);
-            task_queue.push(task_id).expect("Task queue overflowwing");
+            let task_waker = TaskWaker::new(task_id, task.priority, self.ready.clone());
+            let record = Arc::new(TaskRecord {
+                id: task_id,
+                desc: task.desc.clone(),
+                priority: task.priority,
+                task_waker: task_waker.clone(),
+                stats: Mutex::new(PollStats::default()),
+            });
+            self.tasks.insert(task_id, task);
+            let waker = Waker::from(task_waker.clone());
+            task_waker.wake_task();
+            REGISTRY.lock().insert(task_id, record.clone());
+            self.wakers.insert(task_id, TaskSlot { record, waker });
         }
 
-        
-        while let Some(task_id) = task_queue.pop() {            
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
-            };
-            if let Some(ref desc) = task.desc {
-                println!("running task: {}", desc);
+        if self.ready.overflowed.swap(false, Ordering::AcqRel) {
+            self.poll_overflowed();
+        }
+        // Only as many polls as tasks are ready now. Tasks woken during the pass wait for the
+        // next one, otherwise a task that keeps waking itself would never let `run` get back
+        // to new tasks or sleeping.
+        for _ in 0..self.ready.len() {
+            match self.next_ready() {
+                Some(task_id) => self.poll_task(task_id),
+                None => break,
+            }
+        }
+    }
+
+    /// Pick the next task to poll: the highest priority one, unless background tasks have
+    /// waited for too long.
+    fn next_ready(&mut self) -> Option<TaskId> {
+        let background = self.ready.queue(Priority::Background);
+        if self.background_starved >= BACKGROUND_STARVATION_LIMIT {
+            if let Some(task_id) = background.pop() {
+                self.background_starved = 0;
+                return Some(task_id);
             }
-            let waker: &mut Waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(&waker);
-
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    if let Some(ref desc) = task.desc {
-                        println!("task completed: {}", desc);
-                    } else {
-                        println!("task completed: {:?}", task_id);
-                    }
-                    // I think NLL saves us here. But I have no idea how this works re. lifetimes.
-
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        }
+        for &priority in &[Priority::Deferred, Priority::Interactive] {
+            if let Some(task_id) = self.ready.queue(priority).pop() {
+                if background.is_empty() {
+                    self.background_starved = 0;
+                } else {
+                    self.background_starved += 1;
                 }
-                Poll::Pending => {}
+                return Some(task_id);
             }
         }
+        self.background_starved = 0;
+        background.pop()
     }
 
-    pub fn run_simple(&mut self) {
-        while let Some(task_id) = self.task_queue.pop() {
-            let waker = dummy_waker();
-            let mut context = Context::from_waker(&waker);
+    /// Poll the woken tasks that didn't fit into the ready queues, by priority.
+    fn poll_overflowed(&mut self) {
+        let mut woken: Vec<(Priority, TaskId)> = self
+            .wakers
+            .iter()
+            .filter(|(_, slot)| slot.record.task_waker.woken.load(Ordering::Acquire))
+            .map(|(&task_id, slot)| (slot.record.priority, task_id))
+            .collect();
+        woken.sort_unstable();
+        for (_, task_id) in woken {
+            self.poll_task(task_id);
+        }
+    }
 
-            let task = self.tasks.get_mut(&task_id).expect("Task missing");
+    /// Poll `task_id` if it's still woken, it may have been polled since it was queued.
+    fn poll_task(&mut self, task_id: TaskId) {
+        let Self { tasks, wakers, .. } = self;
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return,
+        };
+        let slot = match wakers.get_mut(&task_id) {
+            Some(slot) => slot,
+            None => return,
+        };
+        // Cleared before polling, so wakeups during the poll queue the task again.
+        if !slot.record.task_waker.woken.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(ref desc) = task.desc {
+            trace!("running task: {}", desc);
+        }
+        let mut context = Context::from_waker(&slot.waker);
 
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    println!("Task {:?} completed.", task_id);
-                }
-                Poll::Pending => {
-                    self.task_queue.push(task_id).expect("Tasks queue is full.");
+        let start = Instant::now();
+        let poll = coop::budget(|| task.poll(&mut context));
+        let elapsed = start.elapsed();
+        let report = {
+            let mut stats = slot.record.stats.lock();
+            stats.polls += 1;
+            stats.busy += elapsed;
+            stats.longest_poll = stats.longest_poll.max(elapsed);
+            let slow = elapsed > watchdog_threshold();
+            if slow {
+                stats.slow_polls += 1;
+            }
+            let due = stats
+                .last_report
+                .map_or(true, |last| start.duration_since(last) >= WATCHDOG_REPORT_INTERVAL);
+            if slow && due {
+                stats.last_report = Some(start);
+            }
+            slow && due
+        };
+        if report {
+            deferred::defer(report_slow_polls, task_id.as_u64() as usize).ok();
+        }
+
+        match poll {
+            Poll::Ready(()) => {
+                if let Some(ref desc) = task.desc {
+                    trace!("task completed: {}", desc);
+                } else {
+                    trace!("task completed: {:?}", task_id);
                 }
+                tasks.remove(&task_id);
+                wakers.remove(&task_id);
+                REGISTRY.lock().remove(&task_id);
             }
+            Poll::Pending => {}
+        }
+    }
+
+    pub fn spawn_handle(&self) -> SpawnHandle {
+        SpawnHandle {
+            new_tasks: self.new_tasks.clone(),
+        }
+    }
+
+    /// This executor's live tasks, by id. Tasks spawned since it last ran aren't included.
+    pub fn task_list(&self) -> Vec<TaskInfo> {
+        let now = Instant::now().as_duration();
+        self.wakers.values().map(|slot| slot.record.info(now)).collect()
+    }
+
+    /// Tasks spawned and not finished yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock();
+        for task_id in self.wakers.keys() {
+            registry.remove(task_id);
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct SpawnHandle {
-    tx: Sender<Task>,
+    new_tasks: Arc<SegQueue<Task>>,
 }
 impl SpawnHandle {
-    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static, desc: impl ToString) {
+    pub fn spawn<F>(&mut self, future: F, desc: impl ToString) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (future, handle) = join::wrap(future);
         let task = Task::new(future, desc);
-        self.tx.try_send(task).expect("Spawn channel closed");
+        self.new_tasks.push(task);
+        handle
     }
 }
 
@@ -167,7 +503,12 @@ impl Future for Yield {
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    /// Set when woken, cleared right before the task is polled.
+    woken: AtomicBool,
+    /// Nanoseconds since boot of the last wakeup.
+    woken_at: AtomicU64,
+    ready: Arc<ReadyQueue>,
 }
 
 impl Wake for TaskWaker {
@@ -182,28 +523,23 @@ impl Wake for TaskWaker {
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).ok();
+        // Already queued, or about to be found by the overflow scan.
+        if self.woken.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // The clock sources only lock with interrupts off, so this is fine in a handler.
+        let now = Instant::now().as_duration().as_nanos() as u64;
+        self.woken_at.store(now, Ordering::Relaxed);
+        self.ready.push(self.priority, self.task_id);
     }
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, priority: Priority, ready: Arc<ReadyQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
-            task_queue,
-        }))
+            priority,
+            woken: AtomicBool::new(false),
+            woken_at: AtomicU64::new(0),
+            ready,
+        })
     }
 }
-
-fn no_op(_: *const ()) {}
-fn clone(_: *const ()) -> RawWaker {
-    dummy_raw_waker()
-}
-static DUMMY_RAW_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
-
-fn dummy_raw_waker() -> RawWaker {
-    // 0x4 is the address represening allocated zero sized types.
-    RawWaker::new(0x4 as *const (), &DUMMY_RAW_WAKER_VTABLE)
-}
-
-fn dummy_waker() -> Waker {
-    unsafe { Waker::from_raw(dummy_raw_waker()) }
-}
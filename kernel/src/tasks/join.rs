@@ -0,0 +1,153 @@
+// src/tasks/join.rs
+
+//! Handles to wait for a spawned task's output.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+/// Why a task has no output.
+///
+/// There is no variant for panics: the kernel doesn't unwind, a panicking task takes the
+/// whole system down with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort` was called, or the executor dropped the task.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+impl crate::error::Error for JoinError {}
+
+struct JoinState<T> {
+    output: Mutex<Option<Result<T, JoinError>>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    /// Whoever awaits the `JoinHandle`.
+    joiner: AtomicWaker,
+    /// The task itself, so `abort` can get it polled.
+    task: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, output: Result<T, JoinError>) {
+        *self.output.lock() = Some(output);
+        self.finished.store(true, Ordering::Release);
+        self.joiner.wake();
+    }
+}
+
+/// Wrap `future` so its output can be awaited through the returned handle. The wrapper is
+/// what gets spawned.
+pub(super) fn wrap<F>(future: F) -> (JoinFuture<F>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(JoinState {
+        output: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        joiner: AtomicWaker::new(),
+        task: AtomicWaker::new(),
+    });
+    (
+        JoinFuture {
+            future,
+            state: state.clone(),
+        },
+        JoinHandle { state },
+    )
+}
+
+/// A spawned future, storing its output for the `JoinHandle`.
+pub(super) struct JoinFuture<F: Future> {
+    future: F,
+    state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Future for JoinFuture<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Safety: `future` is never moved out of the pinned wrapper.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.state.aborted.load(Ordering::Acquire) {
+            this.state.finish(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+        this.state.task.register(cx.waker());
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.state.finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for JoinFuture<F> {
+    fn drop(&mut self) {
+        if !self.state.finished.load(Ordering::Acquire) {
+            self.state.finish(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Resolves to the output of a spawned task. Dropping the handle detaches the task, it
+/// keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancel the task. Its future is dropped by the executor the next time it would be
+    /// polled, and the handle resolves to `JoinError::Cancelled` unless the task finished
+    /// first.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task.wake();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(output) = self.state.output.lock().take() {
+            return Poll::Ready(output);
+        }
+        self.state.joiner.register(cx.waker());
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(output),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish_non_exhaustive()
+    }
+}
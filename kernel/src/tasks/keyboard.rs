@@ -6,18 +6,28 @@ use crossbeam::queue::ArrayQueue;
 use futures::{stream::{Stream, StreamExt}, task::AtomicWaker};
 use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts::Us104Key};
 use crate::prelude::*;
+use super::{coop, deferred};
 
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Called from interrupt.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING scancode queue is full. Dropping input");
+            deferred::defer(warn_dropped_scancode, 1).ok();
         } else {
             WAKER.wake();
         }
+    } else {
+        deferred::defer(warn_dropped_scancode, 0).ok();
+    }
+}
+
+fn warn_dropped_scancode(queue_full: usize) {
+    if queue_full != 0 {
+        println!("WARNING scancode queue is full. Dropping input");
     } else {
         println!("WARNING scancode queue uninitialized.");
     }
@@ -44,6 +54,9 @@ impl Stream for ScanCodeStream {
         cx: &mut core::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {                
 
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
         let queue = SCANCODE_QUEUE
             .try_get()
             .expect("SCANCODE_QUEUE not initialized");
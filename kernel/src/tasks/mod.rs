@@ -1,6 +1,9 @@
 // src/tasks.rs
 
+pub mod coop;
+pub mod deferred;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
 pub mod timer;
 pub mod mpsc;
@@ -8,19 +11,54 @@ pub mod mpsc;
 use core::{future::Future, pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Context, Poll}};
 use alloc::prelude::v1::*;
 
+pub use self::join::{JoinError, JoinHandle};
+
 static TASK_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 impl TaskId {
     fn new() -> TaskId {
         TaskId(TASK_COUNTER.fetch_add(1, Ordering::Relaxed))        
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl core::fmt::Display for TaskId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Scheduling class of a task. Ready tasks of a higher class always run first, except that
+/// background tasks get a turn after `executor::BACKGROUND_STARVATION_LIMIT` polls of others.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
+pub enum Priority {
+    /// Work handed off by interrupt handlers, like the timer and deferred work tasks.
+    Deferred = 0,
+    /// Tasks a user waits on, the default.
+    Interactive = 1,
+    /// Long running work, like disk transfers.
+    Background = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Interactive
+    }
 }
 
 pub struct Task {
     id: TaskId,
     desc: Option<String>,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()> + Send>>
 }
 
@@ -28,6 +66,7 @@ impl core::fmt::Debug for Task {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Task")
             .field("id", &self.id)
+            .field("priority", &self.priority)
             .finish_non_exhaustive()
     }
 }
@@ -38,6 +77,7 @@ impl Task {
             id: TaskId::new(),
             future: Box::pin(ts),
             desc: Some(desc.to_string()),
+            priority: Priority::default(),
         }
     }
 
@@ -45,10 +85,23 @@ impl Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(ts),
-            desc: None
+            desc: None,
+            priority: Priority::default(),
         }
     }
-    
+
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
 
     pub fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
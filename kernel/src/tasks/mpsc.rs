@@ -3,6 +3,8 @@ use core::{fmt::Display, result::Result, sync::atomic::{AtomicBool, Ordering}, t
 use crossbeam::queue::{ArrayQueue};
 use futures::{Stream, future::poll_fn, task::AtomicWaker};
 
+use super::coop;
+
 pub fn channel<T>(buffer: usize) -> (Sender<T>, Receiver<T>) {
     let channel = Arc::new(Channel {
         queue: ArrayQueue::new(buffer),
@@ -103,6 +105,9 @@ impl<T> Receiver<T> {
     }
 
     pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if coop::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
         let channel = self.channel.as_ref();
         let closed =  channel.closed.load(Ordering::Relaxed);
 
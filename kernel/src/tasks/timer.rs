@@ -1,16 +1,30 @@
-
-
-use core::{pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Context, Poll}};
-use alloc::{collections::BinaryHeap, sync::Arc};
-use conquer_once::spin::OnceCell;
-use futures::{Future, Stream, StreamExt, stream::select, task::{AtomicWaker}};
-
-#[allow(unused_imports)] use crate::prelude::*;
-use super::{Task, mpsc::{self, Receiver, Sender}};
+// src/tasks/timer.rs
+
+//! Sleeping, driven by the timer interrupt.
+//!
+//! Pending sleeps live in a map ordered by deadline, and a `Sleep` takes its entry out again
+//! when dropped. The timer task wakes expired sleeps and, once the clock no longer depends
+//! on periodic ticks, programs the timer for the next deadline only. While the timer is
+//! periodic, ticks only wake the timer task once the earliest deadline has passed.
+
+use alloc::collections::BTreeMap;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures::{task::AtomicWaker, Future};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{Priority, Task};
+#[allow(unused_imports)]
+use crate::prelude::*;
+use crate::time::{self, Duration, Instant};
 
 static CURRENT_TICK: AtomicU64 = AtomicU64::new(0);
 
-/// Get the last tick that occurred.
+/// Timer interrupts so far. Periodic at `time::TICK_HZ` unless the timer went tickless.
 pub fn current_tick() -> u64 {
     CURRENT_TICK.load(Ordering::SeqCst)
 }
@@ -18,161 +32,195 @@ pub fn current_tick() -> u64 {
 /// Called from interrupt.
 pub(crate) fn next_tick() {
     CURRENT_TICK.fetch_add(1, Ordering::SeqCst);
-    MASTER_WAKER.wake();
-}
-
-#[derive(Debug)]
-enum TimerEvent {
-    Tick(u64),
-    NewTask(PendingTimer)
+    // A tickless interrupt was programmed for a deadline. A periodic one usually isn't due.
+    if time::is_tickless() || deadline_passed() {
+        MASTER_WAKER.wake();
+    }
 }
 
-static MASTER_WAKER: AtomicWaker = AtomicWaker::new();
-static SHARED_HANDLE: OnceCell<TimerHandle> = OnceCell::uninit();
-
-pub unsafe fn init() -> (Task, TimerHandle) {
-    let master_stream = MasterTickStream { last_tick: 0 };
-    let (tx, rx) = mpsc::channel(32);
+/// Sleeps are keyed by deadline, and an id for sleeps sharing one.
+type TimerKey = (Instant, u64);
 
-    let handle = TimerHandle { send: tx };
-    SHARED_HANDLE.init_once(|| handle.clone());
-
-    (Task::no_desc(timer_main(master_stream,  rx)), handle)
+lazy_static! {
+    static ref TIMERS: Mutex<BTreeMap<TimerKey, Waker>> = Mutex::new(BTreeMap::new());
 }
-
-async fn timer_main(
-    ticks: MasterTickStream,
-    new_tasks: Receiver<PendingTimer>
-) {    
-    let mut stream = select(
-        ticks.map(TimerEvent::Tick) ,
-        new_tasks.into_stream().map(TimerEvent::NewTask)
-        );
-
-    let mut queue: BinaryHeap<PendingTimer> = BinaryHeap::new();
-
-    while let Some(ev) = stream.next().await {
-        match ev {
-            TimerEvent::Tick(tick) => {                
-                
-                while queue.peek().map(|t| t.tick <= tick).unwrap_or(false) {
-                    let t = queue.pop().unwrap();
-                    t.waker.wake();
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+static MASTER_WAKER: AtomicWaker = AtomicWaker::new();
+/// Set when a sleep with an earlier deadline than all others was added, so the timer needs
+/// reprogramming.
+static RESCHEDULE: AtomicBool = AtomicBool::new(false);
+/// Earliest deadline in `TIMERS` as nanoseconds since boot, `u64::MAX` if there is none. Only
+/// updated with `TIMERS` locked. A cancelled sleep can leave it early, which costs one
+/// unneeded wakeup.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn set_next_deadline(deadline: Option<Instant>) {
+    let nanos = deadline.map_or(u64::MAX, |deadline| deadline.as_duration().as_nanos() as u64);
+    NEXT_DEADLINE.store(nanos, Ordering::SeqCst);
+}
+
+/// Whether the earliest pending sleep is due. Doesn't lock, so it's safe from interrupts.
+fn deadline_passed() -> bool {
+    let next = NEXT_DEADLINE.load(Ordering::SeqCst);
+    next != u64::MAX && Duration::from_nanos(next) <= Instant::now().as_duration()
+}
+
+/// The task waking sleeps. It has to be spawned for sleeps to finish.
+pub fn init() -> Task {
+    Task::new(timer_main(), "timer").with_priority(Priority::Deferred)
+}
+
+async fn timer_main() {
+    let tickless = time::enable_tickless();
+    println!("timer: {}", if tickless { "tickless" } else { "periodic" });
+
+    let mut last_tick = current_tick();
+    loop {
+        TimerEvent { last_tick }.await;
+        last_tick = current_tick();
+        RESCHEDULE.store(false, Ordering::SeqCst);
+        loop {
+            let next = expire(Instant::now());
+            // In periodic mode the next tick comes anyway.
+            match next {
+                Some(deadline) if tickless => {
+                    if time::program_next_event(deadline) {
+                        break;
+                    }
                 }
-            }
-            TimerEvent::NewTask(task) => {
-                queue.push(task)
+                _ => break,
             }
         }
-
     }
 }
 
-#[derive(Debug)]
-struct PendingTimer {
-    tick: u64,
-    waker: Arc<AtomicWaker>,
-}
-
-impl PendingTimer {
-    fn new(tick: u64) -> PendingTimer {
-        PendingTimer {
-            tick,
-            waker: Arc::new(AtomicWaker::new()),
-        }
+/// Wake every sleep due at `now`. Returns the next deadline.
+fn expire(now: Instant) -> Option<Instant> {
+    // Wakers may get back into timer code, e.g. by dropping a `Sleep`, so they're woken once
+    // the lock is released.
+    let (expired, next) = {
+        let mut timers = TIMERS.lock();
+        let later = timers.split_off(&(now, u64::MAX));
+        let next = later.keys().next().map(|key| key.0);
+        set_next_deadline(next);
+        (core::mem::replace(&mut *timers, later), next)
+    };
+    for (_, waker) in expired {
+        waker.wake();
     }
+    next
 }
 
-impl Ord for PendingTimer {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        // Reversed ordering as alloc's binary heap is a Max-heap and we need a min-heap
-        other.tick.cmp(&self.tick)        
-    }
-}
-impl PartialOrd for PendingTimer {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-impl Eq for PendingTimer {}
-impl PartialEq for PendingTimer {
-    fn eq(&self, other: &Self) -> bool {
-        self.tick == other.tick
-    }
+/// Pending sleeps.
+pub fn pending() -> usize {
+    TIMERS.lock().len()
 }
 
-#[derive(Debug, Clone)]
-pub struct TimerHandle {
-    send: Sender<PendingTimer>
+/// Ready when a timer interrupt came in or the next deadline moved forward.
+struct TimerEvent {
+    last_tick: u64,
 }
 
-impl TimerHandle {
-    pub fn sleep(&mut self, ticks: u64) -> impl Future<Output = ()> {
-        let sleep = Sleep::new(ticks, self.send.clone());
-        sleep
+impl TimerEvent {
+    fn happened(&self) -> bool {
+        current_tick() != self.last_tick || RESCHEDULE.load(Ordering::SeqCst)
     }
 }
 
-pub fn sleep(ticks: u64) -> impl Future<Output = ()> {
-    SHARED_HANDLE.get().expect("Timer task not initalized")
-        .clone()
-        .sleep(ticks)
-}
-
-struct MasterTickStream {
-    last_tick: u64,
-}
-impl Stream for MasterTickStream {
-    type Item = u64;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let tick = current_tick();
+impl Future for TimerEvent {
+    type Output = ();
 
-        if self.last_tick < tick {
-            self.get_mut().last_tick = tick;
-            Poll::Ready(Some(tick))
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.happened() {
+            return Poll::Ready(());
+        }
+        MASTER_WAKER.register(cx.waker());
+        if self.happened() {
+            MASTER_WAKER.take();
+            Poll::Ready(())
         } else {
-            MASTER_WAKER.register(&cx.waker());
-            // Race conditions... YAY!
-            let tick = current_tick();
-            if self.last_tick < tick {
-                MASTER_WAKER.take();
-                self.get_mut().last_tick = tick;
-                Poll::Ready(Some(tick))
-            } else {
-                Poll::Pending
-            }
+            Poll::Pending
         }
     }
 }
 
+/// Wait at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
 
-struct Sleep {
-    tick: u64,
-    waker: Arc<AtomicWaker>
+/// Wait until `deadline` has passed.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+/// Future returned by `sleep`. Dropping it cancels the sleep.
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    /// Entry in `TIMERS`, once polled.
+    key: Option<TimerKey>,
 }
 
 impl Sleep {
-    fn new(ticks: u64, mut register: Sender<PendingTimer>) -> Sleep {
-        let now = current_tick();
-        let tick = now + ticks;
-        let pending_timer = PendingTimer::new(tick);
-        let res = Sleep { tick, waker: pending_timer.waker.clone() };
-        register.try_send(pending_timer).unwrap();
-        res
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn cancel(&mut self) {
+        if let Some(key) = self.key.take() {
+            TIMERS.lock().remove(&key);
+        }
     }
 }
 
 impl Future for Sleep {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let tick = current_tick();
-        if self.tick <= tick {
-            Poll::Ready(())
-        } else {
-            self.waker.register(&cx.waker());
-            Poll::Pending
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.deadline <= Instant::now() {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        let mut timers = TIMERS.lock();
+        match self.key {
+            Some(key) => match timers.get_mut(&key) {
+                Some(waker) => {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                }
+                // Removed by `expire`, so the deadline passed.
+                None => {
+                    drop(timers);
+                    self.key = None;
+                    return Poll::Ready(());
+                }
+            },
+            None => {
+                let key = (self.deadline, NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+                let earliest = timers.keys().next().map_or(true, |first| key < *first);
+                timers.insert(key, cx.waker().clone());
+                if earliest {
+                    set_next_deadline(Some(self.deadline));
+                }
+                drop(timers);
+                self.key = Some(key);
+                if earliest {
+                    RESCHEDULE.store(true, Ordering::SeqCst);
+                    MASTER_WAKER.wake();
+                }
+            }
         }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
     }
 }
@@ -0,0 +1,103 @@
+// src/time/clocksource.rs
+
+//! Counters that time since boot can be read from.
+//!
+//! Every usable source registers in `SOURCES`, and the one with the best rating backs
+//! `Instant::now`. Reading a clock source must not lock or allocate, so timestamps can be
+//! taken from interrupt handlers.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::{hpet::HPET_CLOCK, is_tickless, tick_source, tsc::TSC, TickSource};
+use crate::{pit, tasks::timer};
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better, 0 means the source can't be used.
+    fn rating(&self) -> u32;
+
+    /// Nanoseconds since the source started counting.
+    fn nanos(&self) -> u64;
+}
+
+/// `PIT_CLOCK` has to stay first, see `counts_ticks`.
+static SOURCES: [&dyn ClockSource; 3] = [&PIT_CLOCK, &HPET_CLOCK, &TSC];
+/// Index into `SOURCES`.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// Added to the current source's reading so switching sources doesn't make time jump.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The source `Instant::now` reads.
+pub fn clock_source() -> &'static dyn ClockSource {
+    SOURCES[CURRENT.load(Ordering::Acquire)]
+}
+
+/// Nanoseconds since boot, read from the current clock source.
+pub(super) fn now_nanos() -> u64 {
+    let source = clock_source();
+    source.nanos().wrapping_add(OFFSET.load(Ordering::Acquire))
+}
+
+/// Whether time is read from the timer interrupt count, which rules out tickless timers.
+pub(super) fn counts_ticks() -> bool {
+    CURRENT.load(Ordering::Acquire) == 0
+}
+
+/// Switch to the best rated source. Called again whenever a source becomes usable.
+pub fn select_best() -> &'static dyn ClockSource {
+    interrupts::without_interrupts(|| {
+        let current = CURRENT.load(Ordering::Acquire);
+        let mut best = current;
+        for (index, source) in SOURCES.iter().enumerate() {
+            if source.rating() > SOURCES[best].rating() {
+                best = index;
+            }
+        }
+        if best != current {
+            let now = now_nanos();
+            OFFSET.store(now.wrapping_sub(SOURCES[best].nanos()), Ordering::Release);
+            CURRENT.store(best, Ordering::Release);
+        }
+    });
+    clock_source()
+}
+
+/// The timer tick plus the PIT's count into the current tick.
+pub struct PitClock;
+
+pub static PIT_CLOCK: PitClock = PitClock;
+
+impl PitClock {
+    /// PIT cycles since the timer started.
+    pub fn cycles(&self) -> u64 {
+        let per_tick = pit::cycles_per_tick();
+        // With interrupts off the tick can't move between the two reads.
+        interrupts::without_interrupts(|| {
+            let tick = timer::current_tick();
+            let count = u32::from(pit::current_count());
+            // A count of 0 is the reload value of 65536 in the slowest setting.
+            let elapsed = per_tick.saturating_sub(count) % per_tick;
+            tick * u64::from(per_tick) + u64::from(elapsed)
+        })
+    }
+}
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    /// Only counts while the PIT drives a periodic timer interrupt.
+    fn rating(&self) -> u32 {
+        match tick_source() {
+            TickSource::Pit if !is_tickless() => 100,
+            _ => 0,
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        (u128::from(self.cycles()) * 1_000_000_000 / u128::from(pit::BASE_FREQUENCY)) as u64
+    }
+}
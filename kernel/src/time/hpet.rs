@@ -0,0 +1,428 @@
+// src/time/hpet.rs
+
+//! High precision event timer.
+//!
+//! The HPET is found through its ACPI table. Its main counter is a clock source, and its
+//! comparators raise interrupts: comparator 0 can replace the PIT on IRQ 0 through the
+//! legacy replacement route, the others are claimed with `claim_timer` and routed through
+//! the I/O APIC.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    convert::TryFrom,
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+use super::{clocksource::{self, ClockSource}, tsc, Duration};
+use crate::irq::apic::{self, ApicError, IsaRoute};
+use crate::memory_manager::{map_mmio, CacheMode, MmioError, MmioMapping};
+
+const CAPABILITIES: u64 = 0x000;
+const CONFIG: u64 = 0x010;
+const INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+const CAP_COUNTER_64: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Lets the next comparator write set the period's accumulator in periodic mode.
+const TIMER_SET_VALUE: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+
+/// Register window and capabilities of the HPET.
+pub struct Hpet {
+    base: VirtAddr,
+    /// Main counter period in femtoseconds.
+    period: u64,
+    comparators: u8,
+    counter_64: bool,
+    legacy_route: bool,
+    _mapping: MmioMapping,
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+/// Bit per comparator in use.
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub enum HpetError {
+    /// ACPI describes no HPET, or `init` wasn't called.
+    NotPresent,
+    /// The HPET can't take over the PIT's interrupt line.
+    NoLegacyRoute,
+    /// Every comparator is in use.
+    NoFreeTimer,
+    /// The comparator can't fire periodically.
+    NotPeriodic,
+    /// None of the comparator's interrupt routes exists on the I/O APICs.
+    NoRoute,
+    Apic(ApicError),
+    Map(MmioError),
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HpetError::NotPresent => write!(f, "no HPET described by ACPI"),
+            HpetError::NoLegacyRoute => write!(f, "HPET has no legacy replacement route"),
+            HpetError::NoFreeTimer => write!(f, "all HPET comparators are in use"),
+            HpetError::NotPeriodic => write!(f, "HPET comparator can't run periodically"),
+            HpetError::NoRoute => write!(f, "no usable interrupt route for HPET comparator"),
+            HpetError::Apic(err) => write!(f, "failed to route HPET interrupt: {}", err),
+            HpetError::Map(err) => write!(f, "failed to map HPET registers: {}", err),
+        }
+    }
+}
+
+impl crate::error::Error for HpetError {}
+
+impl From<MmioError> for HpetError {
+    fn from(err: MmioError) -> HpetError {
+        HpetError::Map(err)
+    }
+}
+
+impl From<ApicError> for HpetError {
+    fn from(err: ApicError) -> HpetError {
+        HpetError::Apic(err)
+    }
+}
+
+impl Hpet {
+    fn map(phys: PhysAddr) -> Result<Hpet, HpetError> {
+        let mapping = map_mmio(phys, 0x500, CacheMode::Uncached)?;
+        let mut hpet = Hpet {
+            base: mapping.virt_addr(),
+            period: 0,
+            comparators: 0,
+            counter_64: false,
+            legacy_route: false,
+            _mapping: mapping,
+        };
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period = capabilities >> 32;
+        hpet.comparators = ((capabilities >> 8) & 0x1f) as u8 + 1;
+        hpet.counter_64 = capabilities & CAP_COUNTER_64 != 0;
+        hpet.legacy_route = capabilities & CAP_LEGACY_ROUTE != 0;
+        if hpet.period == 0 {
+            return Err(HpetError::NotPresent);
+        }
+        Ok(hpet)
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { (self.base + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { (self.base + offset).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    fn timer_config(index: u8) -> u64 {
+        0x100 + 0x20 * u64::from(index)
+    }
+
+    fn timer_comparator(index: u8) -> u64 {
+        0x108 + 0x20 * u64::from(index)
+    }
+
+    /// Main counter frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        (FEMTOS_PER_SEC / u128::from(self.period)) as u64
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    fn enable(&self) {
+        let config = self.read(CONFIG);
+        self.write(CONFIG, config | CONFIG_ENABLE);
+    }
+
+    fn stop_timer(&self, index: u8) {
+        let config = self.read(Hpet::timer_config(index));
+        self.write(
+            Hpet::timer_config(index),
+            config & !(TIMER_ENABLE | TIMER_PERIODIC),
+        );
+    }
+
+    /// Program comparator `index` to fire after `cycles`, and every `cycles` after that if
+    /// `periodic`. `extra` is ORed into the timer's config register. Returns the counter
+    /// value the comparator fires at.
+    fn start_timer(&self, index: u8, cycles: u64, periodic: bool, extra: u64) -> u64 {
+        interrupts::without_interrupts(|| {
+            let config_register = Hpet::timer_config(index);
+            let mut config = self.read(config_register)
+                & !(TIMER_LEVEL_TRIGGERED
+                    | TIMER_PERIODIC
+                    | TIMER_32BIT_MODE
+                    | TIMER_ENABLE
+                    | TIMER_ROUTE_MASK);
+            config |= extra;
+            // Disable while reprogramming so a stale comparator can't fire.
+            self.write(config_register, config);
+            let deadline = self.counter().wrapping_add(cycles);
+            if periodic {
+                self.write(config_register, config | TIMER_PERIODIC | TIMER_SET_VALUE);
+                self.write(Hpet::timer_comparator(index), deadline);
+                self.write(Hpet::timer_comparator(index), cycles);
+            } else {
+                self.write(Hpet::timer_comparator(index), deadline);
+            }
+            let config = self.read(config_register) & !TIMER_SET_VALUE;
+            self.write(config_register, config | TIMER_ENABLE);
+            deadline
+        })
+    }
+}
+
+/// Map the HPET at `address`, start its counter and recalibrate the TSC against it.
+pub fn init(address: Option<PhysAddr>) -> Result<&'static Hpet, HpetError> {
+    if let Some(hpet) = HPET.get() {
+        return Ok(hpet);
+    }
+    let hpet = Hpet::map(address.ok_or(HpetError::NotPresent)?)?;
+    for index in 0..hpet.comparators {
+        hpet.stop_timer(index);
+    }
+    hpet.enable();
+    HPET.try_init_once(|| hpet).ok();
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+
+    let frequency = calibrate_tsc(hpet);
+    if frequency != 0 {
+        tsc::set_frequency(frequency);
+    }
+    clocksource::select_best();
+    Ok(hpet)
+}
+
+/// The HPET, once `init` has succeeded.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Measure the TSC frequency against the HPET's main counter, over about 10ms.
+fn calibrate_tsc(hpet: &Hpet) -> u64 {
+    let cycles = cycles_for(Duration::from_millis(10), hpet.period);
+    interrupts::without_interrupts(|| {
+        let start_counter = hpet.counter();
+        let start_tsc = tsc::read();
+        while hpet.counter().wrapping_sub(start_counter) < cycles {}
+        let end_tsc = tsc::read();
+        let elapsed = hpet.counter().wrapping_sub(start_counter);
+        let elapsed_femtos = u128::from(elapsed) * u128::from(hpet.period);
+        (u128::from(end_tsc - start_tsc) * FEMTOS_PER_SEC / elapsed_femtos) as u64
+    })
+}
+
+/// Main counter cycles in `duration`, at least 1.
+fn cycles_for(duration: Duration, period: u64) -> u64 {
+    let femtos = duration.as_nanos() * FEMTOS_PER_NANO;
+    u64::try_from(femtos / u128::from(period)).unwrap_or(u64::MAX).max(1)
+}
+
+/// Drive IRQ 0 from comparator 0 at `frequency` Hz instead of the PIT.
+///
+/// Uses the legacy replacement route, which also takes over IRQ 8 for comparator 1.
+pub(super) fn start_legacy_ticks(frequency: u32) -> Result<(), HpetError> {
+    let hpet = hpet().ok_or(HpetError::NotPresent)?;
+    if !hpet.legacy_route {
+        return Err(HpetError::NoLegacyRoute);
+    }
+    // With the I/O APIC in use the replacement route ends on input 2, which only works if
+    // IRQ 0 is overridden to it.
+    if let Some(route) = apic::isa_route(0) {
+        if route.gsi != 2 {
+            return Err(HpetError::NoLegacyRoute);
+        }
+    }
+    if hpet.read(Hpet::timer_config(0)) & TIMER_PERIODIC_CAP == 0 {
+        return Err(HpetError::NotPeriodic);
+    }
+    if !claim(0b11) {
+        return Err(HpetError::NoFreeTimer);
+    }
+
+    let period = Duration::from_nanos(1_000_000_000 / u64::from(frequency));
+    interrupts::without_interrupts(|| {
+        hpet.start_timer(0, cycles_for(period, hpet.period), true, 0);
+        let config = hpet.read(CONFIG);
+        hpet.write(CONFIG, config | CONFIG_LEGACY_ROUTE);
+    });
+    Ok(())
+}
+
+/// Mark the comparators in `mask` as in use, if none of them is yet.
+fn claim(mask: u32) -> bool {
+    let claimed = CLAIMED.fetch_or(mask, Ordering::SeqCst);
+    if claimed & mask != 0 {
+        // Only give back what this call took.
+        CLAIMED.fetch_and(!(mask & !claimed), Ordering::SeqCst);
+        false
+    } else {
+        true
+    }
+}
+
+/// Make comparator 0 fire once, `after` from now, instead of periodically. Needs
+/// `start_legacy_ticks` first.
+///
+/// Returns false if the counter had already passed the comparator when it was armed, the
+/// interrupt won't come then.
+pub(super) fn start_legacy_one_shot(after: Duration) -> bool {
+    let hpet = match hpet() {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    interrupts::without_interrupts(|| {
+        let deadline = hpet.start_timer(0, cycles_for(after, hpet.period), false, 0);
+        (deadline.wrapping_sub(hpet.counter()) as i64) > 0
+    })
+}
+
+/// A comparator claimed with `claim_timer`, stopped and released when dropped.
+pub struct HpetTimer {
+    index: u8,
+    gsi: u32,
+}
+
+/// Claim a free comparator and route its interrupt to `vector` through the I/O APIC.
+///
+/// The interrupt is level triggered: handlers must call `acknowledge`.
+pub fn claim_timer(vector: u8) -> Result<HpetTimer, HpetError> {
+    let hpet = hpet().ok_or(HpetError::NotPresent)?;
+    let index = (0..hpet.comparators)
+        .find(|&index| claim(1 << index))
+        .ok_or(HpetError::NoFreeTimer)?;
+    // Dropping releases the comparator if routing fails.
+    let mut timer = HpetTimer { index, gsi: 0 };
+
+    // The route field is an input of the first I/O APIC. Inputs below 16 belong to ISA
+    // devices.
+    let routes = hpet.read(Hpet::timer_config(index)) >> 32;
+    let gsi = (16..32)
+        .find(|&gsi| routes & (1 << gsi) != 0 && apic::has_gsi(gsi))
+        .ok_or(HpetError::NoRoute)?;
+    let route = IsaRoute {
+        gsi,
+        active_low: false,
+        level_triggered: true,
+    };
+    apic::route_gsi(gsi, route, vector, false)?;
+    timer.gsi = gsi;
+    Ok(timer)
+}
+
+impl HpetTimer {
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Fire once, `after` from now.
+    pub fn start_one_shot(&self, after: Duration) {
+        let hpet = HPET.get().expect("HPET timer without HPET");
+        let route = u64::from(self.gsi) << TIMER_ROUTE_SHIFT;
+        hpet.start_timer(
+            self.index,
+            cycles_for(after, hpet.period),
+            false,
+            route | TIMER_LEVEL_TRIGGERED,
+        );
+    }
+
+    /// Fire every `period`, starting `period` from now.
+    pub fn start_periodic(&self, period: Duration) -> Result<(), HpetError> {
+        let hpet = HPET.get().expect("HPET timer without HPET");
+        if hpet.read(Hpet::timer_config(self.index)) & TIMER_PERIODIC_CAP == 0 {
+            return Err(HpetError::NotPeriodic);
+        }
+        let route = u64::from(self.gsi) << TIMER_ROUTE_SHIFT;
+        hpet.start_timer(
+            self.index,
+            cycles_for(period, hpet.period),
+            true,
+            route | TIMER_LEVEL_TRIGGERED,
+        );
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(hpet) = HPET.get() {
+            hpet.stop_timer(self.index);
+        }
+    }
+
+    /// Clear the interrupt status, so the level triggered line drops. Call from the
+    /// interrupt handler.
+    pub fn acknowledge(&self) {
+        if let Some(hpet) = HPET.get() {
+            // Status bits are cleared by writing 1.
+            hpet.write(INTERRUPT_STATUS, 1 << self.index);
+        }
+    }
+}
+
+impl Drop for HpetTimer {
+    fn drop(&mut self) {
+        self.stop();
+        CLAIMED.fetch_and(!(1 << self.index), Ordering::SeqCst);
+    }
+}
+
+/// The HPET main counter as a clock source.
+pub struct HpetClock;
+
+pub static HPET_CLOCK: HpetClock = HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    /// A 32 bit counter wraps after a few minutes and isn't used.
+    fn rating(&self) -> u32 {
+        match HPET.get() {
+            Some(hpet) if hpet.counter_64 => 250,
+            _ => 0,
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        match HPET.get() {
+            Some(hpet) => {
+                (u128::from(hpet.counter()) * u128::from(hpet.period) / FEMTOS_PER_NANO) as u64
+            }
+            None => 0,
+        }
+    }
+}
+
+#[test_case]
+fn cycles_for_rounds_down_but_not_to_zero() {
+    // 10MHz counter, 100ns per cycle.
+    let period = 100_000_000;
+    assert_eq!(cycles_for(Duration::from_micros(1), period), 10);
+    assert_eq!(cycles_for(Duration::from_nanos(250), period), 2);
+    assert_eq!(cycles_for(Duration::from_nanos(1), period), 1);
+}
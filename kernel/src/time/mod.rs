@@ -0,0 +1,219 @@
+// src/time/mod.rs
+
+//! Monotonic time since boot.
+//!
+//! Time is read from the best clock source available, see `clocksource`. The timer
+//! interrupt drives sleeping, at `TICK_HZ`, from the PIT or the HPET.
+
+pub mod clocksource;
+pub mod hpet;
+pub mod rtc;
+pub mod tsc;
+
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+pub use self::clocksource::{clock_source, ClockSource};
+pub use self::rtc::{DateTime, SystemTime};
+pub use core::time::Duration;
+
+use crate::{pit, prelude::*};
+
+/// Timer interrupts per second.
+pub const TICK_HZ: u32 = 1000;
+
+/// Latest time handed out, so time never goes backwards, e.g. when a PIT wrap around is seen
+/// before its tick is counted or while switching clock sources.
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+static HPET_TICKS: AtomicBool = AtomicBool::new(false);
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+/// Longest the timer interrupt is put off in tickless mode. About what the PIT can count.
+const MAX_TICKLESS_DELAY: Duration = Duration::from_millis(50);
+
+/// Start ticking at `TICK_HZ`, pick a clock source and set the wall clock from the RTC.
+/// Interrupts should still be off.
+pub fn init() {
+    pit::init(TICK_HZ);
+    tsc::calibrate_with_pit();
+    clocksource::select_best();
+    rtc::sync();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    /// PIT channel 0, set up by `init`.
+    Pit,
+    /// HPET comparator 0 on the PIT's interrupt line. Needs `hpet::init` first.
+    Hpet,
+}
+
+/// Switch the timer interrupt to `preferred` if it's available. Returns the source in use
+/// afterwards.
+pub fn select_tick_source(preferred: TickSource) -> TickSource {
+    if preferred == TickSource::Hpet && tick_source() == TickSource::Pit {
+        if rtc::irq_in_use() {
+            println!("HPET can't drive the timer, staying on the PIT: IRQ 8 is in use by the RTC");
+            return tick_source();
+        }
+        match hpet::start_legacy_ticks(TICK_HZ) {
+            Ok(()) => {
+                HPET_TICKS.store(true, Ordering::SeqCst);
+                clocksource::select_best();
+            }
+            Err(err) => println!("HPET can't drive the timer, staying on the PIT: {}", err),
+        }
+    }
+    tick_source()
+}
+
+pub fn tick_source() -> TickSource {
+    if HPET_TICKS.load(Ordering::SeqCst) {
+        TickSource::Hpet
+    } else {
+        TickSource::Pit
+    }
+}
+
+/// Stop the periodic timer interrupt, from then on it only fires when programmed with
+/// `program_next_event`. Only possible when the clock source doesn't count ticks. Returns
+/// whether the timer is tickless.
+pub(crate) fn enable_tickless() -> bool {
+    if !TICKLESS.load(Ordering::SeqCst) && !clocksource::counts_ticks() {
+        TICKLESS.store(true, Ordering::SeqCst);
+        // Whatever comes first, the next deadline or this, replaces the periodic timer.
+        program_next_event(Instant::now() + MAX_TICKLESS_DELAY);
+    }
+    is_tickless()
+}
+
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::SeqCst)
+}
+
+/// Have the timer interrupt fire at `deadline`, or earlier if that's too far off for the
+/// hardware. Returns false if `deadline` passed already and no interrupt was programmed.
+pub(crate) fn program_next_event(deadline: Instant) -> bool {
+    let delay = deadline.duration_since(Instant::now());
+    if delay == Duration::from_nanos(0) {
+        return false;
+    }
+    let delay = delay.min(MAX_TICKLESS_DELAY);
+    match tick_source() {
+        TickSource::Pit => {
+            let cycles = (delay.as_nanos() * u128::from(pit::BASE_FREQUENCY) + 999_999_999)
+                / 1_000_000_000;
+            pit::start_one_shot(cycles.max(1).min(0xffff) as u16);
+            true
+        }
+        TickSource::Hpet => hpet::start_legacy_one_shot(delay),
+    }
+}
+
+/// A point in time since boot.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        let nanos = clocksource::now_nanos();
+        let last = LAST_NANOS.fetch_max(nanos, Ordering::Relaxed);
+        Instant {
+            nanos: nanos.max(last),
+        }
+    }
+
+    /// Time since boot.
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// Time from `earlier` until `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", self.as_duration())
+    }
+}
+
+#[test_case]
+fn instants_are_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn instant_advances_with_ticks() {
+    use crate::tasks::timer::current_tick;
+
+    let start = Instant::now();
+    let tick = current_tick();
+    while current_tick() < tick + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= Duration::from_nanos(1_000_000_000 / u64::from(TICK_HZ)));
+}
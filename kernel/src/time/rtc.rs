@@ -0,0 +1,549 @@
+// src/time/rtc.rs
+
+//! The CMOS real-time clock and wall-clock time.
+//!
+//! The RTC is read once at boot. `SystemTime` is that reading advanced by the monotonic
+//! clock, and the RTC's update interrupt can be enabled to keep it in step. Those resyncs
+//! only step the wall clock forward, it's slewed back if it runs ahead.
+
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::{tick_source, Duration, Instant, TickSource};
+use crate::irq::{self, HandlerId, InterruptIndex, IrqReturn, RegisterError};
+use crate::tasks::deferred;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_UPDATE_INTERRUPT: u8 = 1 << 4;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_UPDATE: u8 = 1 << 4;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+/// Run `f` with the CMOS. Interrupts are off so the index register can't change under us.
+fn with_cmos<R>(f: impl FnOnce(&mut Cmos) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut CMOS.lock()))
+}
+
+/// A calendar date and time in UTC, as the RTC keeps it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn unix_seconds(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(seconds / 86_400);
+        let time = seconds % 86_400;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+/// ISO 8601, e.g. `2021-03-14T15:09:26Z`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01. Howard Hinnant's `days_from_civil`, for dates from 1970 on.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = u64::from(year) - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = u64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+        + u64::from(day)
+        - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u16, month, day)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Raw register values of one RTC reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+impl RawTime {
+    fn read(cmos: &mut Cmos) -> RawTime {
+        while cmos.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+        RawTime {
+            second: cmos.read(SECONDS),
+            minute: cmos.read(MINUTES),
+            hour: cmos.read(HOURS),
+            day: cmos.read(DAY),
+            month: cmos.read(MONTH),
+            year: cmos.read(YEAR),
+        }
+    }
+
+    /// Decode according to status register B. Two digit years are taken to be from 2000 on.
+    fn decode(&self, status_b: u8) -> DateTime {
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let decode = |value: u8| if binary { value } else { from_bcd(value) };
+        let pm = self.hour & HOUR_PM != 0;
+        let mut hour = decode(self.hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 hour mode counts 12, 1, .., 11.
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+        DateTime {
+            year: 2000 + u16::from(decode(self.year)),
+            month: decode(self.month),
+            day: decode(self.day),
+            hour,
+            minute: decode(self.minute),
+            second: decode(self.second),
+        }
+    }
+}
+
+/// Read the RTC. Reads until two readings agree, so an update can't tear the result.
+pub fn read() -> DateTime {
+    with_cmos(|cmos| {
+        let mut last = RawTime::read(cmos);
+        loop {
+            let next = RawTime::read(cmos);
+            if next == last {
+                return next.decode(cmos.read(STATUS_B));
+            }
+            last = next;
+        }
+    })
+}
+
+/// Backward corrections are spread out, taking back one nanosecond every `SLEW_RATE`.
+const SLEW_RATE: u64 = 2000;
+
+/// Unix time in nanoseconds is `anchor + since_boot - slewed(since_boot)`.
+struct WallClock {
+    /// Unix time in nanoseconds at `Instant` 0. Only `sync` moves it back.
+    anchor: u64,
+    /// How far `anchor` is ahead of the RTC, taken back by slewing.
+    owed: u64,
+    /// Nanoseconds since boot the slew is measured from.
+    slew_start: u64,
+}
+
+impl WallClock {
+    fn slewed(&self, since_boot: u64) -> u64 {
+        (since_boot.saturating_sub(self.slew_start) / SLEW_RATE).min(self.owed)
+    }
+
+    fn unix_nanos(&self, since_boot: u64) -> u64 {
+        self.anchor + since_boot - self.slewed(since_boot)
+    }
+}
+
+static WALL_CLOCK: Mutex<WallClock> = Mutex::new(WallClock {
+    anchor: 0,
+    owed: 0,
+    slew_start: 0,
+});
+/// Latest wall-clock time handed out, so `SystemTime::now` never goes backwards.
+static LAST_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn since_boot_nanos() -> u64 {
+    Instant::now().as_duration().as_nanos() as u64
+}
+
+/// Read the RTC and set the wall clock from it. This is the only way the wall clock goes
+/// back.
+pub fn sync() {
+    let now = read();
+    let unix_nanos = now.unix_seconds() * 1_000_000_000;
+    interrupts::without_interrupts(|| {
+        let mut clock = WALL_CLOCK.lock();
+        clock.anchor = unix_nanos.saturating_sub(since_boot_nanos());
+        clock.owed = 0;
+        LAST_UNIX_NANOS.store(0, Ordering::Relaxed);
+    });
+}
+
+/// Deferred from the update interrupt, with the nanoseconds since boot the interrupt came
+/// in at. That's when the RTC's second started, however late this runs.
+fn resync(update_nanos: usize) {
+    let update_nanos = update_nanos as u64;
+    let unix_nanos = read().unix_seconds() * 1_000_000_000;
+    // The RTC may have moved on to the next second by now.
+    if since_boot_nanos().saturating_sub(update_nanos) >= 900_000_000 {
+        return;
+    }
+    let target = unix_nanos.saturating_sub(update_nanos);
+    interrupts::without_interrupts(|| {
+        let mut clock = WALL_CLOCK.lock();
+        if target >= clock.anchor {
+            // Behind the RTC, jumping forward is fine.
+            clock.anchor = target;
+            clock.owed = 0;
+        } else {
+            // Ahead of it. Keep what was slewed so far so the clock doesn't jump, and take
+            // back the rest gradually.
+            let now = since_boot_nanos();
+            let owed = clock.anchor - target;
+            let slewed = clock.slewed(now).min(owed);
+            clock.owed = owed;
+            clock.slew_start = now.saturating_sub(slewed * SLEW_RATE);
+        }
+    });
+}
+
+/// Wall-clock time, as nanoseconds since the Unix epoch.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    nanos: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemTimeError(Duration);
+
+impl SystemTimeError {
+    /// How far the later time was ahead.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime { nanos: 0 };
+
+    pub fn now() -> SystemTime {
+        let since_boot = since_boot_nanos();
+        let nanos = interrupts::without_interrupts(|| WALL_CLOCK.lock().unix_nanos(since_boot));
+        let last = LAST_UNIX_NANOS.fetch_max(nanos, Ordering::Relaxed);
+        SystemTime {
+            nanos: nanos.max(last),
+        }
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        if self.nanos >= earlier.nanos {
+            Ok(Duration::from_nanos(self.nanos - earlier.nanos))
+        } else {
+            Err(SystemTimeError(Duration::from_nanos(earlier.nanos - self.nanos)))
+        }
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_seconds(self.nanos / 1_000_000_000)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        SystemTime {
+            nanos: self.nanos + duration.as_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        SystemTime {
+            nanos: self.nanos - duration.as_nanos() as u64,
+        }
+    }
+}
+
+impl fmt::Debug for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SystemTime({})", self)
+    }
+}
+
+/// The date and time with milliseconds, e.g. `2021-03-14T15:09:26.535Z`.
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let date_time = self.date_time();
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            date_time.year,
+            date_time.month,
+            date_time.day,
+            date_time.hour,
+            date_time.minute,
+            date_time.second,
+            self.nanos / 1_000_000 % 1000
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcInterrupt {
+    /// Once a second, after the RTC updated. Used to resync the wall clock.
+    Update,
+    /// `32768 >> (rate - 1)` times per second, `rate` from 3 to 15.
+    Periodic { rate: u8 },
+}
+
+#[derive(Debug)]
+pub enum RtcError {
+    /// The HPET's legacy replacement route owns IRQ 8.
+    IrqTaken,
+    InvalidRate(u8),
+    Register(RegisterError),
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtcError::IrqTaken => write!(f, "IRQ 8 is routed to the HPET"),
+            RtcError::InvalidRate(rate) => write!(f, "invalid RTC periodic rate {}", rate),
+            RtcError::Register(err) => write!(f, "failed to register RTC handler: {:?}", err),
+        }
+    }
+}
+
+impl crate::error::Error for RtcError {}
+
+static HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Enable `interrupt` on IRQ 8. Update interrupts resync `SystemTime` with the RTC.
+pub fn enable_interrupt(interrupt: RtcInterrupt) -> Result<(), RtcError> {
+    if tick_source() == TickSource::Hpet {
+        return Err(RtcError::IrqTaken);
+    }
+    if let RtcInterrupt::Periodic { rate } = interrupt {
+        if !(3..=15).contains(&rate) {
+            return Err(RtcError::InvalidRate(rate));
+        }
+    }
+    {
+        let mut handler = HANDLER.lock();
+        if handler.is_none() {
+            let id = irq::register_irq(InterruptIndex::RTC.irq(), "rtc", rtc_handler, 0)
+                .map_err(RtcError::Register)?;
+            *handler = Some(id);
+        }
+    }
+    with_cmos(|cmos| {
+        let status_b = cmos.read(STATUS_B);
+        match interrupt {
+            RtcInterrupt::Update => {
+                cmos.write(STATUS_B, status_b | STATUS_B_UPDATE_INTERRUPT);
+            }
+            RtcInterrupt::Periodic { rate } => {
+                let status_a = cmos.read(STATUS_A);
+                cmos.write(STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+                cmos.write(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+            }
+        }
+        // Nothing new is raised until status C has been read.
+        cmos.read(STATUS_C);
+    });
+    Ok(())
+}
+
+/// Turn RTC interrupts off and remove the handler.
+pub fn disable_interrupts() {
+    with_cmos(|cmos| {
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(
+            STATUS_B,
+            status_b & !(STATUS_B_UPDATE_INTERRUPT | STATUS_B_PERIODIC_INTERRUPT),
+        );
+        cmos.read(STATUS_C);
+    });
+    if let Some(id) = HANDLER.lock().take() {
+        irq::unregister(id);
+    }
+}
+
+/// Whether anything handles IRQ 8. The HPET's legacy replacement route would take it away.
+pub(super) fn irq_in_use() -> bool {
+    HANDLER.lock().is_some() || irq::has_handlers(InterruptIndex::RTC.as_u8())
+}
+
+/// Update interrupts seen so far.
+pub fn updates() -> u64 {
+    UPDATES.load(Ordering::Relaxed)
+}
+
+/// Periodic interrupts seen so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn rtc_handler(_: usize) -> IrqReturn {
+    let status_c = with_cmos(|cmos| cmos.read(STATUS_C));
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & STATUS_C_UPDATE != 0 {
+        UPDATES.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now().as_duration().as_nanos() as usize;
+        deferred::defer(resync, now).ok();
+    }
+    IrqReturn::Handled
+}
+
+#[test_case]
+fn resync_never_moves_the_clock_back() {
+    let mut last = SystemTime::now();
+    let mut check = || {
+        let now = SystemTime::now();
+        assert!(now >= last);
+        last = now;
+    };
+    // An update half a second ago puts the clock ahead, one in the future behind.
+    resync(since_boot_nanos().saturating_sub(500_000_000) as usize);
+    check();
+    resync((since_boot_nanos() + 500_000_000) as usize);
+    check();
+    resync((since_boot_nanos() + 900_000_000) as usize);
+    for _ in 0..1000 {
+        check();
+    }
+    sync();
+}
+
+#[test_case]
+fn bcd_and_twelve_hour_times_decode() {
+    let raw = RawTime {
+        second: 0x59,
+        minute: 0x30,
+        hour: HOUR_PM | 0x12,
+        day: 0x29,
+        month: 0x02,
+        year: 0x24,
+    };
+    let date_time = raw.decode(0);
+    assert_eq!(
+        date_time,
+        DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 30,
+            second: 59,
+        }
+    );
+    let midnight = RawTime { hour: 0x12, ..raw }.decode(0);
+    assert_eq!(midnight.hour, 0);
+    let binary = RawTime { hour: 23, ..raw }.decode(STATUS_B_BINARY | STATUS_B_24_HOUR);
+    assert_eq!(binary.hour, 23);
+}
+
+#[test_case]
+fn unix_time_round_trips() {
+    let date_time = DateTime {
+        year: 2021,
+        month: 3,
+        day: 14,
+        hour: 15,
+        minute: 9,
+        second: 26,
+    };
+    assert_eq!(date_time.unix_seconds(), 1_615_734_566);
+    assert_eq!(DateTime::from_unix_seconds(1_615_734_566), date_time);
+    assert_eq!(DateTime::from_unix_seconds(0).year, 1970);
+}
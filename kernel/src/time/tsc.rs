@@ -0,0 +1,124 @@
+// src/time/tsc.rs
+
+//! The time stamp counter as a clock source.
+//!
+//! The TSC is only trusted when CPUID reports it invariant, that is running at a constant
+//! rate regardless of frequency scaling and sleep states. Its frequency isn't reported
+//! reliably, so it's measured against a reference clock at boot.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::clocksource::ClockSource;
+use crate::pit;
+
+/// PIT cycles per calibration run, about 10ms.
+const CALIBRATION_PIT_CYCLES: u16 = 11_932;
+const CALIBRATION_RUNS: usize = 3;
+
+pub struct Tsc {
+    calibrated: AtomicBool,
+    invariant: AtomicBool,
+    frequency: AtomicU64,
+    /// TSC value counted as 0ns.
+    base: AtomicU64,
+    /// Nanoseconds per cycle as a 32.32 fixed point number.
+    nanos_per_cycle: AtomicU64,
+}
+
+pub static TSC: Tsc = Tsc {
+    calibrated: AtomicBool::new(false),
+    invariant: AtomicBool::new(false),
+    frequency: AtomicU64::new(0),
+    base: AtomicU64::new(0),
+    nanos_per_cycle: AtomicU64::new(0),
+};
+
+/// Current value of the time stamp counter.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether CPUID reports an invariant TSC.
+pub fn is_invariant() -> bool {
+    unsafe {
+        let max_extended = __cpuid(0x8000_0000).eax;
+        max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// Measured TSC frequency in Hz, once calibrated.
+pub fn frequency() -> Option<u64> {
+    if TSC.calibrated.load(Ordering::Acquire) {
+        Some(TSC.frequency.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Measure the TSC frequency against PIT channel 2. Takes about 30ms.
+pub fn calibrate_with_pit() -> u64 {
+    let cycles = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_RUNS)
+            .map(|_| pit::measure_with_channel_2(CALIBRATION_PIT_CYCLES, read))
+            .min()
+            .unwrap_or(0)
+    });
+    let frequency =
+        cycles * u64::from(pit::BASE_FREQUENCY) / u64::from(CALIBRATION_PIT_CYCLES);
+    set_frequency(frequency);
+    frequency
+}
+
+/// Use `frequency` from now on, e.g. after measuring it against a better reference.
+pub fn set_frequency(frequency: u64) {
+    if frequency == 0 {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        // Keep the time read so far when the frequency is corrected.
+        let base = if TSC.calibrated.load(Ordering::Relaxed) {
+            let cycles = u128::from(TSC.nanos()) * u128::from(frequency) / 1_000_000_000;
+            read().wrapping_sub(cycles as u64)
+        } else {
+            read()
+        };
+        TSC.invariant.store(is_invariant(), Ordering::Relaxed);
+        TSC.frequency.store(frequency, Ordering::Relaxed);
+        TSC.nanos_per_cycle.store(
+            ((1_000_000_000u128 << 32) / u128::from(frequency)) as u64,
+            Ordering::Relaxed,
+        );
+        TSC.base.store(base, Ordering::Relaxed);
+        TSC.calibrated.store(true, Ordering::Release);
+    });
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if self.calibrated.load(Ordering::Acquire) && self.invariant.load(Ordering::Relaxed) {
+            300
+        } else {
+            0
+        }
+    }
+
+    fn nanos(&self) -> u64 {
+        let cycles = read().wrapping_sub(self.base.load(Ordering::Relaxed));
+        let nanos_per_cycle = self.nanos_per_cycle.load(Ordering::Relaxed);
+        ((u128::from(cycles) * u128::from(nanos_per_cycle)) >> 32) as u64
+    }
+}
+
+#[test_case]
+fn tsc_frequency_is_plausible() {
+    // `crate::init` calibrates, this only fails if the measurement went badly wrong.
+    let frequency = frequency().expect("TSC not calibrated");
+    assert!(frequency > 100_000_000, "TSC at {} Hz", frequency);
+    assert!(frequency < 20_000_000_000, "TSC at {} Hz", frequency);
+}
@@ -0,0 +1,56 @@
+// tests/exception_oops.rs
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use dumb_os::prelude::*;
+use dumb_os::qemu::{exit_qemu, ExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    dumb_os::init();
+    print!("exception_oops::invalid_opcode_is_reported... ");
+
+    unsafe { asm!("ud2") };
+
+    println!("[failed]\n\nError: continued after ud2");
+    exit_qemu(ExitCode::Failed);
+    loop {}
+}
+
+/// Keeps the first bytes written to it.
+struct Buffer {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut buffer = Buffer {
+        bytes: [0; 128],
+        len: 0,
+    };
+    write!(buffer, "{}", info).ok();
+    let message = core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap_or("");
+
+    if message.contains("kernel oops: invalid opcode") {
+        println!("[ok]");
+        exit_qemu(ExitCode::Success);
+    } else {
+        println!("[failed]\n\nError: {}", info);
+        exit_qemu(ExitCode::Failed);
+    }
+    loop {}
+}
@@ -10,10 +10,15 @@
 extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
-use dumb_os::{allocator::{self, HEAP_SIZE}, memory::{self, BootInfoBumpAllocator}};
+use dumb_os::{
+    allocator::{self, stats, HEAP_INITIAL_SIZE},
+    memory::{self, BitmapFrameAllocator},
+    memory_manager::{self, MemoryManager},
+};
 use x86_64::VirtAddr;
 use core::panic::PanicInfo;
 use alloc::prelude::v1::*;
+use alloc::vec;
 
 entry_point!(main);
 
@@ -23,10 +28,14 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().expect("physical_memory_offset"));
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoBumpAllocator::init(boot_info)
+        BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("Failed to initialize heap");
+    memory_manager::init(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
 
     test_main();
     loop {}
@@ -55,12 +64,78 @@ fn large_vec() {
 
 #[test_case]
 fn many_allocations() {
-    for i in 0..HEAP_SIZE {
+    for i in 0..HEAP_INITIAL_SIZE {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
 }
 
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let size = 4 * HEAP_INITIAL_SIZE as usize;
+    let vec = vec![0xa5u8; size];
+    assert!(allocator::heap_size() >= size as u64);
+    assert!(vec.iter().all(|&b| b == 0xa5));
+}
+
+#[test_case]
+fn workload_does_not_leak() {
+    let before = stats::snapshot();
+    {
+        let boxes: Vec<Box<u64>> = (0..100).map(Box::new).collect();
+        let mut s = String::new();
+        for b in &boxes {
+            s.push_str(&b.to_string());
+        }
+        assert!(!s.is_empty());
+    }
+    let diff = stats::snapshot().since(&before);
+    assert!(!diff.leaked(), "workload leaked: {}", diff);
+    assert!(diff.allocations >= 101);
+}
+
+#[test_case]
+fn stats_count_live_allocations() {
+    let before = stats::snapshot();
+    let value = Box::new([0u8; 100]);
+    let diff = stats::snapshot().since(&before);
+    assert_eq!(diff.live_bytes, 100);
+    assert!(diff.leaked());
+    drop(value);
+    assert!(!stats::snapshot().since(&before).leaked());
+}
+
+#[test_case]
+fn tracking_records_live_allocations() {
+    stats::set_tracking(true);
+    let value = Box::new([0u64; 8]);
+    let address = &*value as *const _ as usize;
+    let mut found = None;
+    stats::tracked_allocations(|a| {
+        if a.address == address {
+            found = Some(a.size);
+        }
+    });
+    stats::set_tracking(false);
+    assert_eq!(found, Some(64));
+}
+
+#[test_case]
+fn tracking_records_a_caller() {
+    stats::set_tracking(true);
+    let value = Box::new(7u32);
+    let address = &*value as *const _ as usize;
+    let mut caller = None;
+    stats::tracked_allocations(|a| {
+        if a.address == address {
+            caller = Some(a.caller);
+        }
+    });
+    stats::set_tracking(false);
+    let caller = caller.expect("allocation was tracked");
+    assert_ne!(caller, 0);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     dumb_os::test_panic_handler(info)
@@ -0,0 +1,220 @@
+// tests/memory.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dumb_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use dumb_os::{
+    allocator::{self, HEAP_START},
+    memory::{self, protection, BitmapFrameAllocator, MappingSize},
+    memory_manager::{
+        self, allocate_dma, fault, map_mmio, CacheMode, DmaConstraints, MemoryManager,
+        VirtualRegionAllocator,
+    },
+};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    dumb_os::init();
+    let phys_mem_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("physical_memory_offset"),
+    );
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory_manager::init(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn virtual_regions_do_not_overlap_and_are_reused() {
+    let mut regions = VirtualRegionAllocator::new(VirtAddr::new(0x1000_0000), 0x10_0000);
+    let a = regions.allocate(0x3000, 0x1000).unwrap();
+    let b = regions.allocate(0x1000, 0x4000).unwrap();
+    assert!(b >= a + 0x3000u64);
+    assert_eq!(b.as_u64() % 0x4000, 0);
+
+    regions.free(a, 0x3000);
+    regions.free(b, 0x1000);
+    // Everything merged back together.
+    assert_eq!(regions.allocate(0x10_0000, 0x1000), Some(VirtAddr::new(0x1000_0000)));
+    assert_eq!(regions.allocate(0x1000, 0x1000), None);
+}
+
+#[test_case]
+fn freeing_free_regions_is_detected() {
+    let base = VirtAddr::new(0x1000_0000);
+    let mut regions = VirtualRegionAllocator::new(base, 0x10_0000);
+    let a = regions.allocate(0x2000, 0x1000).unwrap();
+    let b = regions.allocate(0x2000, 0x1000).unwrap();
+    let c = regions.allocate(0x2000, 0x1000).unwrap();
+    assert!(!regions.overlaps_free(a, 0x6000));
+
+    regions.free(b, 0x2000);
+    // Starting where a free range starts, with a different size.
+    assert!(regions.overlaps_free(b, 0x1000));
+    // Running into the next free range.
+    assert!(regions.overlaps_free(a, 0x3000));
+    // Starting inside a free range.
+    assert!(regions.overlaps_free(b + 0x1000u64, 0x2000));
+    assert!(!regions.overlaps_free(a, 0x2000));
+    assert!(!regions.overlaps_free(c, 0x2000));
+}
+
+#[test_case]
+fn mmio_mapping_aliases_physical_memory() {
+    // The VGA text buffer is always there.
+    let vga = PhysAddr::new(0xb8000);
+    let first = map_mmio(vga, 2, CacheMode::Uncached).expect("map vga");
+    let second = map_mmio(vga, 2, CacheMode::WriteThrough).expect("map vga again");
+    assert_ne!(first.virt_addr(), second.virt_addr());
+
+    unsafe {
+        first.as_ptr::<u16>().write_volatile(0x0f41);
+        assert_eq!(second.as_ptr::<u16>().read_volatile(), 0x0f41);
+    }
+}
+
+#[test_case]
+fn lazy_region_is_backed_on_first_touch() {
+    let size = 4 * 4096;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = fault::allocate_lazy(size, flags).expect("reserve lazy region");
+    let used_before = memory_manager::memory_manager()
+        .lock()
+        .frame_allocator
+        .used_frames();
+
+    let ptr = start.as_mut_ptr::<u64>();
+    unsafe {
+        // Fresh pages come back zeroed.
+        assert_eq!(ptr.add(512 * 2).read_volatile(), 0);
+        ptr.add(512 * 2).write_volatile(0xdead_beef);
+        assert_eq!(ptr.add(512 * 2).read_volatile(), 0xdead_beef);
+    }
+    let used_after = memory_manager::memory_manager()
+        .lock()
+        .frame_allocator
+        .used_frames();
+    // The page that was touched got a frame, maybe along with new page tables.
+    assert!(used_after > used_before);
+
+    fault::free_lazy(start, size);
+    let used_freed = memory_manager::memory_manager()
+        .lock()
+        .frame_allocator
+        .used_frames();
+    assert_eq!(used_freed, used_after - 1);
+}
+
+#[test_case]
+fn walker_reports_heap_and_mmio_mappings() {
+    let vga = map_mmio(PhysAddr::new(0xb8000), 2, CacheMode::Uncached).expect("map vga");
+    let memory_manager = memory_manager::memory_manager().lock();
+    let walker = memory_manager.walker();
+
+    let heap = walker
+        .query(VirtAddr::new(HEAP_START))
+        .expect("heap is mapped");
+    assert_eq!(heap.size, MappingSize::Size4KiB);
+    assert!(heap.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+
+    assert_eq!(walker.translate(vga.virt_addr()), Some(PhysAddr::new(0xb8000)));
+    assert_eq!(
+        walker.translate(vga.virt_addr() + 1u64),
+        Some(PhysAddr::new(0xb8001))
+    );
+    let vga_page = walker.query(vga.virt_addr()).unwrap();
+    assert!(vga_page.flags.contains(PageTableFlags::NO_CACHE));
+    assert_eq!(walker.query(VirtAddr::new(0x7000_0000_0000)), None);
+
+    // Ranges come out sorted, merged and agree with `query`.
+    let mut previous_end = VirtAddr::new(0);
+    let mut found_vga = false;
+    for range in walker.ranges() {
+        assert!(range.start >= previous_end);
+        assert!(range.len > 0);
+        previous_end = range.end();
+        if range.contains(vga.virt_addr()) {
+            found_vga = true;
+            assert_eq!(range.flags, vga_page.flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY);
+        }
+    }
+    assert!(found_vga);
+}
+
+#[test_case]
+fn no_page_is_writable_and_executable() {
+    let memory_manager = memory_manager::memory_manager().lock();
+    let walker = memory_manager.walker();
+    if let Some(range) = protection::w_xor_x_violations(&walker).next() {
+        panic!("writable and executable: {}", range);
+    }
+
+    let heap = walker.query(VirtAddr::new(HEAP_START)).unwrap();
+    assert!(heap.flags.contains(PageTableFlags::NO_EXECUTE));
+    let (text_start, text_end) = protection::kernel_text();
+    let code = VirtAddr::new(protection::enable as usize as u64);
+    assert!(text_start <= code && code < text_end);
+    let text = walker.query(text_start).unwrap();
+    assert!(!text.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!text.flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn dma_buffers_meet_constraints() {
+    let constraints = DmaConstraints::isa().align(0x4000);
+    // Any page tables needed to map the buffer stay around, get them allocated first.
+    drop(allocate_dma(3 * 4096 + 100, constraints).expect("allocate dma"));
+    let free_before = memory_manager::memory_manager()
+        .lock()
+        .frame_allocator
+        .free_frames();
+    {
+        let mut buffer = allocate_dma(3 * 4096 + 100, constraints).expect("allocate dma");
+        let phys = buffer.phys_addr().as_u64();
+        let end = phys + buffer.len() as u64;
+        assert_eq!(phys % 0x4000, 0);
+        assert!(end <= 0x100_0000);
+        assert_eq!(phys / 0x1_0000, (end - 1) / 0x1_0000);
+        assert!(buffer.iter().all(|&b| b == 0));
+
+        buffer[4096] = 0xab;
+        let memory_manager = memory_manager::memory_manager().lock();
+        let walker = memory_manager.walker();
+        for page in 0..4 {
+            let offset = page * 4096u64;
+            assert_eq!(
+                walker.translate(buffer.virt_addr() + offset),
+                Some(buffer.phys_addr() + offset)
+            );
+        }
+    }
+    let free_after = memory_manager::memory_manager()
+        .lock()
+        .frame_allocator
+        .free_frames();
+    assert_eq!(free_after, free_before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dumb_os::test_panic_handler(info)
+}
@@ -3,13 +3,23 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
 
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use volatile::Volatile;
-use dumb_os::print;
+use dumb_os::{allocator, print};
+use dumb_os::memory::{self, BitmapFrameAllocator};
+use dumb_os::memory_manager::{self, fault, KernelStack, MemoryManager};
 use dumb_os::qemu::{ExitCode, exit_qemu};
 use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+const STACK_OWNER: &str = "stack_overflow test";
 
 lazy_static! {
     static ref TEST_IDT: InterruptDescriptorTable = {
@@ -31,22 +41,57 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    print!("[ok]\n");
-    exit_qemu(ExitCode::Success);
+    // The overflow has to be caught by the guard page of the stack we ran on.
+    let in_guard = fault::guard_owner(Cr2::read(), |owner| owner == Some(STACK_OWNER));
+    if in_guard {
+        print!("[ok]\n");
+        exit_qemu(ExitCode::Success);
+    } else {
+        print!("[failed]\n\nError: fault at {:?} outside the guard page\n", Cr2::read());
+        exit_qemu(ExitCode::Failed);
+    }
     loop {}
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    // No `dumb_os::init()`, interrupts must stay off with the test IDT loaded.
+    dumb_os::io::stdio_init();
     print!("stack_overflow::stack_overflow... ");
 
     dumb_os::gdt::init();
+    let phys_mem_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("physical_memory_offset"),
+    );
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory_manager::init(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
     init_test_idt();
 
-    stack_overflow();
+    let stack = KernelStack::new(16 * 1024, STACK_OWNER).expect("allocate stack");
+    unsafe {
+        asm!(
+            "mov rsp, {0}",
+            "call {1}",
+            in(reg) stack.top().as_u64(),
+            sym overflow_on_new_stack,
+            options(noreturn)
+        );
+    }
+}
 
+extern "C" fn overflow_on_new_stack() -> ! {
+    stack_overflow();
     panic!("Continued after stack_overflow");
-    
 }
 
 #[allow(unconditional_recursion)]
@@ -61,4 +106,3 @@ pub fn stack_overflow() {
 fn panic(info: &PanicInfo) -> ! {
     dumb_os::test_panic_handler(info)
 }
-
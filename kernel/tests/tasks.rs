@@ -0,0 +1,383 @@
+// tests/tasks.rs
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(dumb_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use dumb_os::{
+    allocator,
+    memory::{self, BitmapFrameAllocator},
+    memory_manager::{self, MemoryManager},
+    tasks::{
+        coop,
+        deferred::{self, DeferError},
+        executor::{self, spawn, yield_task, Executor, TaskState},
+        mpsc, timer, JoinError, Priority, Task,
+    },
+    time::{self, Duration, Instant},
+};
+use futures::task::noop_waker_ref;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    dumb_os::init();
+    let phys_mem_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("physical_memory_offset"),
+    );
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+    memory_manager::init(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
+    // Only the queue is needed, the deferred work task itself is never polled here.
+    let _ = deferred::init();
+
+    test_main();
+    loop {}
+}
+
+static DEFERRED_SUM: AtomicUsize = AtomicUsize::new(0);
+
+fn add_deferred(data: usize) {
+    // Multiply first so the result depends on the order the items ran in.
+    let sum = DEFERRED_SUM.load(Ordering::SeqCst);
+    DEFERRED_SUM.store(sum * 10 + data, Ordering::SeqCst);
+}
+
+#[test_case]
+fn deferred_work_runs_in_order() {
+    DEFERRED_SUM.store(0, Ordering::SeqCst);
+    deferred::defer(add_deferred, 1).unwrap();
+    deferred::defer(add_deferred, 2).unwrap();
+    deferred::defer(add_deferred, 3).unwrap();
+    assert_eq!(deferred::run_pending(), 3);
+    assert_eq!(DEFERRED_SUM.load(Ordering::SeqCst), 123);
+    assert_eq!(deferred::run_pending(), 0);
+}
+
+#[test_case]
+fn breakpoints_are_reported_from_task_context() {
+    deferred::run_pending();
+    x86_64::instructions::interrupts::int3();
+    // The handler only queued the report, running it prints the frame.
+    assert_eq!(deferred::run_pending(), 1);
+}
+
+#[test_case]
+fn deferring_to_a_full_queue_fails() {
+    let dropped = deferred::dropped();
+    for _ in 0..deferred::QUEUE_SIZE {
+        deferred::defer(add_deferred, 0).unwrap();
+    }
+    assert_eq!(deferred::defer(add_deferred, 0), Err(DeferError::Full));
+    assert_eq!(deferred::dropped(), dropped + 1);
+    assert_eq!(deferred::run_pending(), deferred::QUEUE_SIZE);
+}
+
+#[test_case]
+fn dropped_sleeps_are_cancelled() {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let before = timer::pending();
+    let mut sleeps: alloc::vec::Vec<_> = (0..1000)
+        .map(|i| timer::sleep(Duration::from_secs(60 + i)))
+        .collect();
+    for sleep in sleeps.iter_mut() {
+        assert_eq!(Pin::new(sleep).poll(&mut cx), Poll::Pending);
+    }
+    assert_eq!(timer::pending(), before + 1000);
+    sleeps.truncate(10);
+    assert_eq!(timer::pending(), before + 10);
+    drop(sleeps);
+    assert_eq!(timer::pending(), before);
+}
+
+#[test_case]
+fn expired_sleep_is_ready() {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut sleep = timer::sleep_until(Instant::now());
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Ready(()));
+    assert_eq!(timer::pending(), 0);
+}
+
+fn wait_ticks(ticks: u64) {
+    let start = timer::current_tick();
+    while timer::current_tick() < start + ticks {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn periodic_ticks_wake_the_timer_only_when_a_sleep_is_due() {
+    let mut executor = Executor::new();
+    executor.spawn_task(timer::init());
+    executor.run_ready_tasks();
+    if time::is_tickless() {
+        // Every interrupt is programmed for a deadline then, nothing to check.
+        return;
+    }
+
+    wait_ticks(5);
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_list()[0].polls, 1);
+
+    let mut sleep = timer::sleep(Duration::from_millis(2));
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Pending);
+    executor.run_ready_tasks();
+    let polls = executor.task_list()[0].polls;
+
+    wait_ticks(5);
+    executor.run_ready_tasks();
+    assert!(executor.task_list()[0].polls > polls);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut cx), Poll::Ready(()));
+}
+
+#[test_case]
+fn join_handle_yields_output() {
+    let mut executor = Executor::new();
+    let mut handle = spawn(async { 40 + 2 }, "answer");
+    assert!(!handle.is_finished());
+    executor.run_ready_tasks();
+    assert!(handle.is_finished());
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert_eq!(Pin::new(&mut handle).poll(&mut cx), Poll::Ready(Ok(42)));
+}
+
+static GUARD_DROPPED: AtomicBool = AtomicBool::new(false);
+
+struct DropGuard;
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        GUARD_DROPPED.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn aborted_task_is_dropped() {
+    let mut executor = Executor::new();
+    let guard = DropGuard;
+    let mut handle = spawn(
+        async move {
+            let _guard = guard;
+            futures::future::pending::<u32>().await
+        },
+        "forever",
+    );
+    let mut cx = Context::from_waker(noop_waker_ref());
+    executor.run_ready_tasks();
+    assert_eq!(Pin::new(&mut handle).poll(&mut cx), Poll::Pending);
+
+    handle.abort();
+    executor.run_ready_tasks();
+    assert!(GUARD_DROPPED.load(Ordering::SeqCst));
+    assert_eq!(
+        Pin::new(&mut handle).poll(&mut cx),
+        Poll::Ready(Err(JoinError::Cancelled))
+    );
+}
+
+static YIELDED_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+#[test_case]
+fn wakeups_beyond_the_ready_queue_are_not_lost() {
+    let mut executor = Executor::new();
+    let count = 2 * executor::READY_QUEUE_SIZE;
+    YIELDED_TASKS.store(0, Ordering::SeqCst);
+    for _ in 0..count {
+        let task = Task::no_desc(async {
+            yield_task().await;
+            YIELDED_TASKS.fetch_add(1, Ordering::SeqCst);
+        });
+        executor.spawn_task(task);
+    }
+    // Every task yields once, so two rounds have to be enough.
+    executor.run_ready_tasks();
+    executor.run_ready_tasks();
+    assert_eq!(YIELDED_TASKS.load(Ordering::SeqCst), count);
+    assert_eq!(executor.task_count(), 0);
+}
+
+static RUN_ORDER: AtomicUsize = AtomicUsize::new(0);
+
+#[test_case]
+fn higher_priority_tasks_run_first() {
+    let mut executor = Executor::new();
+    RUN_ORDER.store(0, Ordering::SeqCst);
+    for &priority in &[Priority::Background, Priority::Interactive, Priority::Deferred] {
+        let task = Task::no_desc(async move {
+            let order = RUN_ORDER.load(Ordering::SeqCst);
+            RUN_ORDER.store(order * 10 + priority as usize + 1, Ordering::SeqCst);
+        });
+        executor.spawn_task(task.with_priority(priority));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(RUN_ORDER.load(Ordering::SeqCst), 123);
+}
+
+static BUSY_POLLS: AtomicUsize = AtomicUsize::new(0);
+static BACKGROUND_RAN_AFTER: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+#[test_case]
+fn background_tasks_are_not_starved() {
+    let mut executor = Executor::new();
+    BUSY_POLLS.store(0, Ordering::SeqCst);
+    let busy = Task::no_desc(async {
+        for _ in 0..10 * executor::BACKGROUND_STARVATION_LIMIT {
+            BUSY_POLLS.fetch_add(1, Ordering::SeqCst);
+            yield_task().await;
+        }
+    });
+    let background = Task::no_desc(async {
+        BACKGROUND_RAN_AFTER.store(BUSY_POLLS.load(Ordering::SeqCst), Ordering::SeqCst);
+    });
+    executor.spawn_task(busy);
+    executor.spawn_task(background.with_priority(Priority::Background));
+    // The busy task is woken again every poll, so it takes one pass per yield.
+    for _ in 0..20 * executor::BACKGROUND_STARVATION_LIMIT {
+        executor.run_ready_tasks();
+    }
+    assert!(BACKGROUND_RAN_AFTER.load(Ordering::SeqCst) <= executor::BACKGROUND_STARVATION_LIMIT);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn task_list_reports_live_tasks() {
+    let mut executor = Executor::new();
+    let task = Task::new(futures::future::pending::<()>(), "idle");
+    let id = task.id();
+    executor.spawn_task(task);
+    executor.run_ready_tasks();
+
+    let tasks = executor.task_list();
+    assert_eq!(tasks.len(), 1);
+    let info = &tasks[0];
+    assert_eq!(info.id, id);
+    assert_eq!(info.desc.as_deref(), Some("idle"));
+    assert_eq!(info.priority, Priority::Interactive);
+    assert_eq!(info.state, TaskState::Pending);
+    assert_eq!(info.polls, 1);
+}
+
+#[test_case]
+fn budget_forces_busy_receivers_to_yield() {
+    let mut executor = Executor::new();
+    let count = 2 * coop::BUDGET as usize;
+    let (mut tx, mut rx) = mpsc::channel(count);
+    for i in 0..count {
+        tx.try_send(i).unwrap();
+    }
+    let task = Task::new(
+        async move {
+            while rx.recv().await.is_some() {}
+        },
+        "receiver",
+    );
+    executor.spawn_task(task);
+    for _ in 0..10 {
+        executor.run_ready_tasks();
+    }
+
+    // Two polls run out of budget, the last one finds the channel empty.
+    let tasks = executor.task_list();
+    assert_eq!(tasks[0].polls, 3);
+    assert_eq!(tasks[0].state, TaskState::Pending);
+    assert_eq!(coop::remaining(), None);
+    drop(tx);
+}
+
+#[test_case]
+fn watchdog_reports_slow_polls() {
+    let mut executor = Executor::new();
+    executor::set_watchdog_threshold(Duration::from_millis(1));
+    let task = Task::new(
+        async {
+            loop {
+                let start = Instant::now();
+                while start.elapsed() < Duration::from_millis(5) {}
+                yield_task().await;
+            }
+        },
+        "slow",
+    );
+    executor.spawn_task(task);
+    deferred::run_pending();
+    executor.run_ready_tasks();
+    executor.run_ready_tasks();
+    executor::set_watchdog_threshold(executor::DEFAULT_WATCHDOG_THRESHOLD);
+
+    let tasks = executor.task_list();
+    assert_eq!(tasks[0].slow_polls, 2);
+    assert!(tasks[0].longest_poll >= Duration::from_millis(5));
+    // Only the first slow poll queued a report, the second came too soon after it.
+    assert_eq!(deferred::run_pending(), 1);
+}
+
+static LATE_TASK_RAN: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn yielding_task_does_not_block_new_tasks() {
+    let mut executor = Executor::new();
+    let spinner = Task::no_desc(async {
+        loop {
+            yield_task().await;
+        }
+    });
+    executor.spawn_task(spinner);
+    executor.run_ready_tasks();
+
+    let late = Task::no_desc(async {
+        LATE_TASK_RAN.store(true, Ordering::SeqCst);
+    });
+    executor.spawn_task(late);
+    executor.run_ready_tasks();
+    assert!(LATE_TASK_RAN.load(Ordering::SeqCst));
+    assert_eq!(executor.task_count(), 1);
+}
+
+static LISTED_ITSELF: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn task_list_is_readable_from_tasks() {
+    let mut executor = Executor::new();
+    let lister = Task::new(
+        async {
+            let tasks = executor::task_list();
+            let found = tasks
+                .iter()
+                .any(|info| info.desc.as_deref() == Some("lister") && info.polls == 0);
+            LISTED_ITSELF.store(found, Ordering::SeqCst);
+        },
+        "lister",
+    );
+    let id = lister.id();
+    executor.spawn_task(lister);
+    executor.run_ready_tasks();
+    assert!(LISTED_ITSELF.load(Ordering::SeqCst));
+    assert!(executor::task_list().iter().all(|info| info.id != id));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    dumb_os::test_panic_handler(info)
+}